@@ -0,0 +1,643 @@
+// Server Configuration
+//
+// Settings come from an optional TOML file, passed with `--config` or found
+// at `$XDG_CONFIG_HOME/pty-server/config.toml` (`%APPDATA%\pty-server\config.toml`
+// on Windows), overridden by command line flags. The file also defines named
+// shell profiles, so clients can start a session with
+// `{"type":"init","profile":"python-venv"}` instead of sending the full
+// shell, arguments and environment.
+use crate::environment::EnvPolicy;
+use crate::logging::{Format, Level};
+use crate::paste::ControlChars;
+use crate::pty_session::SpawnOptions;
+use crate::shared::{SizePolicy, Sharing};
+use crate::shell::LaunchMode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// Effective server settings
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSettings,
+    pub auth: AuthSettings,
+    pub limits: LimitSettings,
+    pub terminal: TerminalSettings,
+    pub buffers: BufferSettings,
+    pub log: LogSettings,
+    pub metrics: MetricsSettings,
+    pub web: WebSettings,
+    pub sharing: SharingSettings,
+    pub persistence: PersistenceSettings,
+    pub instance: InstanceSettings,
+    pub paste: PasteSettings,
+    /// Environment policy for sessions whose init doesn't set one
+    pub env_policy: EnvPolicy,
+    /// Named shell profiles
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// How clients connect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// WebSocket over TCP on `bind`:`port`
+    Tcp,
+    /// WebSocket over a Unix domain socket at `socket`
+    Unix,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind: IpAddr,
+    /// 0 picks a random port
+    pub port: u16,
+    pub transport: Transport,
+    /// Socket path for the unix transport
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket: Option<PathBuf>,
+    /// Capture the login shell's environment as the base for sessions
+    pub login_env: bool,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 0,
+            transport: Transport::Tcp,
+            socket: None,
+            login_env: false,
+        }
+    }
+}
+
+/// Token clients must present, as `Authorization: Bearer <token>` or `?token=`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// File holding the token, read at startup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    /// Sessions running at the same time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
+    /// Largest WebSocket message accepted from clients
+    pub max_message_bytes: usize,
+    /// Input messages waiting for the PTY before clients have to wait
+    pub max_queued_input: usize,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        Self { max_sessions: None, max_message_bytes: 16 << 20, max_queued_input: 64 }
+    }
+}
+
+/// Size of the PTY until the client sends a resize
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TerminalSettings {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl Default for TerminalSettings {
+    fn default() -> Self {
+        Self { cols: 80, rows: 24 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BufferSettings {
+    /// Size of a single PTY read
+    pub pty_read: usize,
+    /// Interval for polling the PTY foreground process group
+    pub foreground_poll_ms: u64,
+    /// Frames queued for a client before it is disconnected as too slow
+    pub client_queue: usize,
+}
+
+impl Default for BufferSettings {
+    fn default() -> Self {
+        Self { pty_read: 8192, foreground_poll_ms: 500, client_queue: 1024 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    pub level: Level,
+    pub format: Format,
+    /// Colored level labels when stderr is a terminal
+    pub color: bool,
+    /// Also append log lines to this file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// Size at which the log file is rotated
+    pub max_file_bytes: u64,
+    /// Rotated log files kept
+    pub max_files: usize,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            level: Level::default(),
+            format: Format::Text,
+            color: true,
+            file: None,
+            max_file_bytes: 10 << 20,
+            max_files: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSettings {
+    /// Serve Prometheus metrics at `http://<listen>/metrics`, loopback only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen: Option<SocketAddr>,
+}
+
+/// Browser terminal at `/terminal`, requires `auth.token`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSettings {
+    pub enabled: bool,
+    /// Profile, shell and cwd used when the page URL doesn't set them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Vault for `{vault}` in profiles and hyperlinks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_root: Option<String>,
+}
+
+/// Defaults for sessions with several clients, init can override them
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SharingSettings {
+    pub mode: Sharing,
+    pub size_policy: SizePolicy,
+}
+
+/// Saving sessions to restore them after a restart
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceSettings {
+    pub enabled: bool,
+    /// Saved sessions file [default: `$XDG_STATE_HOME/pty-server/sessions.json`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// How often running sessions are saved
+    pub interval_secs: u64,
+    /// Output kept per session and replayed on restore
+    pub scrollback_bytes: usize,
+    /// Ended sessions kept for restoring, newest first
+    pub max_restorable: usize,
+}
+
+impl Default for PersistenceSettings {
+    fn default() -> Self {
+        Self { enabled: false, file: None, interval_secs: 10, scrollback_bytes: 64 << 10, max_restorable: 20 }
+    }
+}
+
+/// How `paste` messages are written to the PTY
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasteSettings {
+    pub control_chars: ControlChars,
+    /// Bytes written at a time
+    pub chunk_bytes: usize,
+    /// Pause between chunks, for the program to read them
+    pub chunk_delay_ms: u64,
+}
+
+impl Default for PasteSettings {
+    fn default() -> Self {
+        Self { control_chars: ControlChars::Strip, chunk_bytes: 1024, chunk_delay_ms: 10 }
+    }
+}
+
+/// One server shared by all clients of the user
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstanceSettings {
+    /// Later launches print the running instance's endpoint and exit
+    pub shared: bool,
+    /// Directory of the lock and endpoint files [default: `$XDG_RUNTIME_DIR/pty-server`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime_dir: Option<PathBuf>,
+}
+
+/// Named shell setup selected by `profile` in init
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Shown in the profile picker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Shell type as in init (bash, zsh, custom:/path, ...)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    /// Working directory, `~`, `{home}` and `{vault}` are expanded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    pub login: bool,
+    pub interactive: bool,
+    /// Load shell integration (cwd tracking)
+    pub integration: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env_policy: Option<EnvPolicy>,
+    pub limits: ProfileLimits,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            description: None,
+            shell: None,
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            cwd: None,
+            login: false,
+            interactive: false,
+            integration: true,
+            env_policy: None,
+            limits: ProfileLimits::default(),
+        }
+    }
+}
+
+/// Resource limits of sessions started from a profile
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileLimits {
+    /// Sessions of this profile running at the same time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
+    /// Sessions are closed after this many seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_lifetime_secs: Option<u64>,
+}
+
+/// Profile as listed to clients
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileEntry {
+    pub name: String,
+    #[serde(flatten)]
+    pub profile: Profile,
+}
+
+/// Invalid or unreadable configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Invalid config {}: {}", path.display(), e),
+            ConfigError::Invalid(message) => write!(f, "Invalid configuration: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Profile that can't be used for a session
+#[derive(Debug)]
+pub enum ProfileError {
+    NotFound(String),
+    /// The cwd template can't be expanded, e.g. `{vault}` without a vault root
+    Cwd(String, String),
+    LimitReached(String, usize),
+}
+
+impl ProfileError {
+    /// Stable error code reported to clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProfileError::NotFound(_) => "profile_not_found",
+            ProfileError::Cwd(..) => "invalid_profile",
+            ProfileError::LimitReached(..) => "profile_limit_reached",
+        }
+    }
+}
+
+impl std::fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProfileError::NotFound(name) => write!(f, "Profile not found: {}", name),
+            ProfileError::Cwd(name, e) => write!(f, "Profile {}: cannot expand cwd: {}", name, e),
+            ProfileError::LimitReached(name, max) => {
+                write!(f, "Profile {} already has {} running sessions", name, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+impl Config {
+    /// Load the config file from `path`, or from the default location if it exists.
+    /// Call `finish` once command line overrides are applied.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let text = std::fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path, e))
+    }
+
+    /// Read the token file and check every setting
+    pub fn finish(&mut self) -> Result<(), ConfigError> {
+        if let Some(path) = &self.auth.token_file {
+            let token = std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+            self.auth.token = Some(token.trim().to_string());
+        }
+        if self.persistence.enabled && self.persistence.file.is_none() {
+            self.persistence.file = default_sessions_path();
+        }
+        if self.instance.shared && self.instance.runtime_dir.is_none() {
+            self.instance.runtime_dir = default_runtime_dir();
+        }
+        self.validate().map_err(ConfigError::Invalid)
+    }
+
+    /// Settings as TOML with the token hidden, for `--print-config`
+    pub fn to_redacted_toml(&self) -> String {
+        let mut config = self.clone();
+        if config.auth.token.is_some() {
+            config.auth.token = Some("<redacted>".to_string());
+        }
+        toml::to_string_pretty(&config).unwrap_or_default()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let server = &self.server;
+        if server.transport == Transport::Unix {
+            if cfg!(not(unix)) {
+                return Err("server.transport = \"unix\" is only available on Unix".to_string());
+            }
+            if server.socket.is_none() {
+                return Err("server.socket is required with the unix transport".to_string());
+            }
+        }
+        if self.auth.token.as_deref().is_some_and(str::is_empty) {
+            return Err("auth.token is empty".to_string());
+        }
+        if server.transport == Transport::Tcp && !server.bind.is_loopback() && self.auth.token.is_none() {
+            return Err(format!("binding to {} requires auth.token or auth.token_file", server.bind));
+        }
+        if self.limits.max_sessions == Some(0) {
+            return Err("limits.max_sessions must be at least 1".to_string());
+        }
+        if self.limits.max_message_bytes < 1024 {
+            return Err("limits.max_message_bytes must be at least 1024".to_string());
+        }
+        if !(1..=65_536).contains(&self.limits.max_queued_input) {
+            return Err("limits.max_queued_input must be between 1 and 65536".to_string());
+        }
+        let terminal = &self.terminal;
+        if !(1..=1000).contains(&terminal.cols) || !(1..=1000).contains(&terminal.rows) {
+            return Err(format!("terminal size {}x{} must be between 1 and 1000", terminal.cols, terminal.rows));
+        }
+        if !(1024..=1 << 20).contains(&self.buffers.pty_read) {
+            return Err("buffers.pty_read must be between 1024 and 1048576".to_string());
+        }
+        if !(50..=60_000).contains(&self.buffers.foreground_poll_ms) {
+            return Err("buffers.foreground_poll_ms must be between 50 and 60000".to_string());
+        }
+        if !(16..=65_536).contains(&self.buffers.client_queue) {
+            return Err("buffers.client_queue must be between 16 and 65536".to_string());
+        }
+        if self.log.max_file_bytes < 4096 {
+            return Err("log.max_file_bytes must be at least 4096".to_string());
+        }
+        if self.web.enabled && self.auth.token.is_none() {
+            return Err("web.enabled requires auth.token or auth.token_file".to_string());
+        }
+        let persistence = &self.persistence;
+        if persistence.enabled && persistence.file.is_none() {
+            return Err("persistence.file is required, no state directory found".to_string());
+        }
+        if self.instance.shared && self.instance.runtime_dir.is_none() {
+            return Err("instance.runtime_dir is required, no runtime directory found".to_string());
+        }
+        if persistence.interval_secs == 0 {
+            return Err("persistence.interval_secs must be at least 1".to_string());
+        }
+        if persistence.scrollback_bytes > 4 << 20 {
+            return Err("persistence.scrollback_bytes must be at most 4194304".to_string());
+        }
+        if !(16..=1 << 20).contains(&self.paste.chunk_bytes) {
+            return Err("paste.chunk_bytes must be between 16 and 1048576".to_string());
+        }
+        if self.paste.chunk_delay_ms > 1000 {
+            return Err("paste.chunk_delay_ms must be at most 1000".to_string());
+        }
+        if let Some(listen) = self.metrics.listen {
+            if !listen.ip().is_loopback() {
+                return Err(format!("metrics.listen {} must be a loopback address", listen));
+            }
+        }
+
+        for (name, profile) in &self.profiles {
+            if let Some(cwd) = &profile.cwd {
+                expand_cwd(cwd, Some("")).map_err(|e| format!("profile {}: cwd: {}", name, e))?;
+            }
+            if profile.limits.max_sessions == Some(0) {
+                return Err(format!("profile {}: limits.max_sessions must be at least 1", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Profiles in name order, for the profile picker
+    pub fn profile_entries(&self) -> Vec<ProfileEntry> {
+        self.profiles
+            .iter()
+            .map(|(name, profile)| ProfileEntry { name: name.clone(), profile: profile.clone() })
+            .collect()
+    }
+}
+
+impl Profile {
+    /// Spawn options of the profile, init fields are layered on top by the caller
+    pub fn spawn_options(&self, name: &str, vault_root: Option<&str>, env_policy: &EnvPolicy) -> Result<SpawnOptions, ProfileError> {
+        let cwd = match &self.cwd {
+            Some(template) => Some(expand_cwd(template, vault_root).map_err(|e| ProfileError::Cwd(name.to_string(), e))?),
+            None => None,
+        };
+
+        Ok(SpawnOptions {
+            shell_type: self.shell.clone(),
+            shell_args: Some(self.args.clone()).filter(|args| !args.is_empty()),
+            mode: LaunchMode { login: self.login, interactive: self.interactive },
+            cwd,
+            env: (!self.env.is_empty()).then(|| self.env.clone().into_iter().collect()),
+            env_policy: self.env_policy.clone().unwrap_or_else(|| env_policy.clone()),
+            integration: self.integration,
+            ..SpawnOptions::default()
+        })
+    }
+}
+
+/// Default config location
+pub fn default_path() -> Option<PathBuf> {
+    #[cfg(windows)]
+    let dir = std::env::var_os("APPDATA").map(PathBuf::from);
+
+    #[cfg(not(windows))]
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| PathBuf::from(home).join(".config")));
+
+    dir.map(|dir| dir.join("pty-server").join("config.toml"))
+}
+
+/// Default saved sessions file
+pub fn default_sessions_path() -> Option<PathBuf> {
+    #[cfg(windows)]
+    let dir = std::env::var_os("LOCALAPPDATA").map(PathBuf::from);
+
+    #[cfg(not(windows))]
+    let dir = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| PathBuf::from(home).join(".local").join("state")));
+
+    dir.map(|dir| dir.join("pty-server").join("sessions.json"))
+}
+
+/// Default directory of the shared instance's lock and endpoint files
+pub fn default_runtime_dir() -> Option<PathBuf> {
+    #[cfg(not(unix))]
+    let dir = std::env::var_os("LOCALAPPDATA").map(|dir| PathBuf::from(dir).join("pty-server"));
+
+    // Without XDG_RUNTIME_DIR (macOS) a per-user directory in the temp dir
+    #[cfg(unix)]
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| PathBuf::from(dir).join("pty-server"))
+        .or_else(|| Some(std::env::temp_dir().join(format!("pty-server-{}", unsafe { libc::getuid() }))));
+
+    dir
+}
+
+fn home_dir() -> Option<String> {
+    let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    std::env::var(var).ok().filter(|home| !home.is_empty())
+}
+
+/// Expand `~`, `{home}` and `{vault}` in a cwd template
+fn expand_cwd(template: &str, vault_root: Option<&str>) -> Result<String, String> {
+    let template = match template.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("{{home}}{}", rest),
+        _ => template.to_string(),
+    };
+
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or("unclosed '{'")? + start;
+        match &rest[start + 1..end] {
+            "home" => expanded.push_str(&home_dir().ok_or("home directory unknown")?),
+            "vault" => expanded.push_str(vault_root.ok_or("{vault} used without vault_root")?),
+            other => return Err(format!("unknown placeholder {{{}}}", other)),
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profiles() {
+        let config: Config = toml::from_str(
+            r#"
+            [profiles.python-venv]
+            shell = "bash"
+            cwd = "{vault}/scripts"
+            env = { VIRTUAL_ENV = "/opt/venv" }
+            limits = { max_sessions = 2 }
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let profile = &config.profiles["python-venv"];
+        assert!(profile.integration);
+        let options = profile.spawn_options("python-venv", Some("/vault"), &EnvPolicy::default()).unwrap();
+        assert_eq!(options.cwd.as_deref(), Some("/vault/scripts"));
+        assert_eq!(options.env.unwrap()["VIRTUAL_ENV"], "/opt/venv");
+
+        let error = profile.spawn_options("python-venv", None, &EnvPolicy::default()).unwrap_err();
+        assert_eq!(error.code(), "invalid_profile");
+        assert!(toml::from_str::<Config>("[profiles.x]\nshel = \"bash\"").is_err());
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(toml::from_str::<Config>("[server]\nbind = \"localhost:80\"").is_err());
+        assert!(toml::from_str::<Config>("[log]\nlevel = \"loud\"").is_err());
+
+        let mut config: Config = toml::from_str("[terminal]\ncols = 0").unwrap();
+        assert!(config.finish().is_err());
+        let mut config: Config = toml::from_str("[server]\ntransport = \"unix\"").unwrap();
+        assert!(config.finish().is_err());
+        let mut config: Config = toml::from_str("[server]\nbind = \"0.0.0.0\"").unwrap();
+        assert!(config.finish().is_err());
+        let mut config: Config = toml::from_str("[persistence]\ninterval_secs = 0").unwrap();
+        assert!(config.finish().is_err());
+        let mut config: Config = toml::from_str("[paste]\nchunk_bytes = 0").unwrap();
+        assert!(config.finish().is_err());
+
+        let mut config = Config::default();
+        config.finish().unwrap();
+        let printed: Config = toml::from_str(&config.to_redacted_toml()).unwrap();
+        assert_eq!(printed.terminal.cols, 80);
+    }
+
+    #[test]
+    fn test_expand_cwd() {
+        assert_eq!(expand_cwd("/tmp/{vault}", Some("v")).unwrap(), "/tmp/v");
+        assert!(expand_cwd("{nope}", None).is_err());
+        assert!(expand_cwd("{vault", Some("v")).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+// Session Environment
+//
+// Shells inherit the server's environment, which is Obsidian's own when the
+// server is started by the plugin. Electron variables like
+// ELECTRON_RUN_AS_NODE and NODE_OPTIONS break Node tooling in the shell, so
+// the inherited environment is filtered through a policy before the session's
+// own variables are layered on top.
+//
+// When started from a desktop launcher the server only has a minimal PATH.
+// Optionally the user's login environment is captured once by running
+// `$SHELL -l -i -c env` and used as the base instead.
+use portable_pty::CommandBuilder;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+/// Time allowed for the login shell to print its environment
+const LOGIN_ENV_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Printed before the dump so output of rc files can be skipped
+const LOGIN_ENV_MARKER: &str = "__SW_LOGIN_ENV__";
+
+/// Printed after the dump, the capture is complete once it is seen
+const LOGIN_ENV_END: &str = "__SW_LOGIN_ENV_END__";
+
+/// Prints the environment as NUL-separated `KEY=VALUE` entries. `env -0` isn't
+/// available on older macOS and busybox, POSIX awk is.
+const LOGIN_ENV_DUMP: &str =
+    r#"env -0 2>/dev/null || awk 'BEGIN{for(k in ENVIRON) printf "%s=%s%c",k,ENVIRON[k],0}'"#;
+
+/// Variables describing the capturing shell itself, not the login environment
+const LOGIN_ENV_SKIP: &[&str] = &["PWD", "OLDPWD", "SHLVL", "_"];
+
+/// Variables kept by the default allowlist
+#[cfg(not(windows))]
+const DEFAULT_ALLOW: &[&str] = &[
+    "HOME", "USER", "LOGNAME", "SHELL", "PATH", "TMPDIR", "LANG", "LC_*", "TZ",
+    "DISPLAY", "WAYLAND_DISPLAY", "XDG_*", "DBUS_SESSION_BUS_ADDRESS", "SSH_AUTH_SOCK",
+];
+
+#[cfg(windows)]
+const DEFAULT_ALLOW: &[&str] = &[
+    "SYSTEMROOT", "SYSTEMDRIVE", "WINDIR", "COMSPEC", "PATH", "PATHEXT", "TEMP", "TMP",
+    "USERNAME", "USERDOMAIN", "USERPROFILE", "HOMEDRIVE", "HOMEPATH", "APPDATA",
+    "LOCALAPPDATA", "PROGRAMDATA", "PROGRAMFILES", "PROGRAMFILES(X86)", "COMPUTERNAME",
+];
+
+/// Variables removed by default, set by Electron for Obsidian itself
+const DEFAULT_DENY: &[&str] = &["ELECTRON_*", "NODE_OPTIONS"];
+
+/// Which inherited variables reach the shell
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvMode {
+    /// Inherit everything
+    #[default]
+    Inherit,
+    /// Inherit only variables matching `allow`
+    Allowlist,
+    /// Inherit nothing
+    Clean,
+}
+
+/// Environment policy, variables passed in init are always set on top
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EnvPolicy {
+    pub mode: EnvMode,
+    /// Names kept in allowlist mode, a trailing `*` matches a prefix
+    pub allow: Vec<String>,
+    /// Names removed in every mode, a trailing `*` matches a prefix
+    pub deny: Vec<String>,
+}
+
+impl Default for EnvPolicy {
+    fn default() -> Self {
+        Self {
+            mode: EnvMode::Inherit,
+            allow: DEFAULT_ALLOW.iter().map(|s| s.to_string()).collect(),
+            deny: DEFAULT_DENY.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl EnvPolicy {
+    /// Filter the environment inherited by `cmd`
+    pub fn apply(&self, cmd: &mut CommandBuilder) {
+        let inherited: Vec<(String, String)> = cmd
+            .iter_full_env_as_str()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        cmd.env_clear();
+        for (key, value) in inherited {
+            if self.keeps(&key) {
+                cmd.env(key, value);
+            }
+        }
+    }
+
+    /// Whether an inherited variable is kept
+    fn keeps(&self, key: &str) -> bool {
+        let allowed = match self.mode {
+            EnvMode::Inherit => true,
+            EnvMode::Allowlist => self.allow.iter().any(|pattern| matches(pattern, key)),
+            EnvMode::Clean => false,
+        };
+        allowed && !self.deny.iter().any(|pattern| matches(pattern, key))
+    }
+}
+
+/// Environment sessions start from, before the policy is applied
+#[derive(Debug, Clone)]
+pub enum BaseEnv {
+    /// The server's own environment
+    Server,
+    /// Captured from the user's login shell
+    Login(Arc<Vec<(String, String)>>),
+    /// Capturing failed, the server's environment is used
+    LoginFailed(String),
+}
+
+impl BaseEnv {
+    /// Variables replacing the server's environment, if any
+    pub fn vars(&self) -> Option<Arc<Vec<(String, String)>>> {
+        match self {
+            BaseEnv::Login(vars) => Some(Arc::clone(vars)),
+            BaseEnv::Server | BaseEnv::LoginFailed(_) => None,
+        }
+    }
+}
+
+/// Base environment shared by all sessions, the login environment is captured on first use
+pub struct BaseEnvCache {
+    capture_login: bool,
+    cell: OnceCell<BaseEnv>,
+}
+
+impl BaseEnvCache {
+    pub fn new(capture_login: bool) -> Self {
+        Self { capture_login, cell: OnceCell::new() }
+    }
+
+    /// Get the base environment, concurrent callers wait for a single capture
+    pub async fn get(&self) -> &BaseEnv {
+        self.cell
+            .get_or_init(|| async {
+                if !self.capture_login {
+                    return BaseEnv::Server;
+                }
+                // Runs the user's rc files, keep it off the runtime thread
+                match tokio::task::spawn_blocking(capture_login_env).await {
+                    Ok(Ok(vars)) => BaseEnv::Login(Arc::new(vars)),
+                    Ok(Err(e)) => BaseEnv::LoginFailed(e.to_string()),
+                    Err(e) => BaseEnv::LoginFailed(e.to_string()),
+                }
+            })
+            .await
+    }
+}
+
+/// Why the login environment could not be captured
+#[derive(Debug)]
+pub enum LoginEnvError {
+    /// Couldn't start the shell
+    Spawn(String, std::io::Error),
+    /// The shell didn't finish in time, e.g. an rc file waits for input
+    Timeout(String),
+    /// The shell exited without printing its environment
+    NoOutput(String, std::process::ExitStatus),
+    /// Capturing is only done on Unix
+    #[cfg(not(unix))]
+    Unsupported,
+}
+
+impl std::fmt::Display for LoginEnvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginEnvError::Spawn(shell, e) => write!(f, "Cannot start {} to capture the login environment: {}", shell, e),
+            LoginEnvError::Timeout(shell) => {
+                write!(f, "{} did not print its login environment within {}s", shell, LOGIN_ENV_TIMEOUT.as_secs())
+            }
+            LoginEnvError::NoOutput(shell, status) => {
+                write!(f, "{} exited without printing its login environment ({})", shell, status)
+            }
+            #[cfg(not(unix))]
+            LoginEnvError::Unsupported => write!(f, "Login environment capture is not supported on this platform"),
+        }
+    }
+}
+
+impl std::error::Error for LoginEnvError {}
+
+/// Run the user's shell as an interactive login shell and collect its environment.
+/// Blocks for up to `LOGIN_ENV_TIMEOUT`, call from a blocking context.
+#[cfg(unix)]
+pub fn capture_login_env() -> Result<Vec<(String, String)>, LoginEnvError> {
+    use std::io::Read;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+    use std::sync::mpsc::RecvTimeoutError;
+
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+    let script = format!("echo {}; {}; echo {}", LOGIN_ENV_MARKER, LOGIN_ENV_DUMP, LOGIN_ENV_END);
+    let mut child = Command::new(&shell)
+        .args(["-l", "-i", "-c", &script])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        // Own process group, so jobs started by rc files are killed with the shell
+        .process_group(0)
+        .spawn()
+        .map_err(|e| LoginEnvError::Spawn(shell.clone(), e))?;
+
+    // Read on a separate thread, a background job started by an rc file may keep the pipe open
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut chunk = [0u8; 8192];
+        while let Ok(n) = stdout.read(&mut chunk) {
+            if n == 0 || tx.send(chunk[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Stop at the end marker rather than at EOF, which an rc file may delay forever
+    let deadline = std::time::Instant::now() + LOGIN_ENV_TIMEOUT;
+    let end = format!("{}\n", LOGIN_ENV_END);
+    let mut output = Vec::new();
+    let mut timed_out = false;
+    while !output.windows(end.len()).any(|w| w == end.as_bytes()) {
+        match rx.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now())) {
+            Ok(chunk) => output.extend_from_slice(&chunk),
+            Err(RecvTimeoutError::Timeout) => {
+                timed_out = true;
+                break;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let status = child.wait().map_err(|e| LoginEnvError::Spawn(shell.clone(), e))?;
+
+    // After a timeout whatever was dumped so far is still better than the server's environment
+    match parse_env_output(&output) {
+        Some(vars) => Ok(vars),
+        None if timed_out => Err(LoginEnvError::Timeout(shell)),
+        None => Err(LoginEnvError::NoOutput(shell, status)),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn capture_login_env() -> Result<Vec<(String, String)>, LoginEnvError> {
+    Err(LoginEnvError::Unsupported)
+}
+
+/// Parse the NUL-separated dump following the marker line. Only entries
+/// ended by a NUL are complete, the rest of a cut-off dump is ignored.
+fn parse_env_output(output: &[u8]) -> Option<Vec<(String, String)>> {
+    let marker = format!("{}\n", LOGIN_ENV_MARKER);
+    let start = output.windows(marker.len()).position(|w| w == marker.as_bytes())? + marker.len();
+    let end = start + output[start..].iter().rposition(|&b| b == 0)?;
+
+    let vars: Vec<(String, String)> = output[start..end]
+        .split(|&b| b == 0)
+        .filter_map(|entry| {
+            let entry = String::from_utf8_lossy(entry);
+            let (key, value) = entry.split_once('=')?;
+            (!key.is_empty() && !LOGIN_ENV_SKIP.contains(&key)).then(|| (key.to_string(), value.to_string()))
+        })
+        .collect();
+    (!vars.is_empty()).then_some(vars)
+}
+
+/// Match a variable name against `NAME` or `PREFIX*`, case-insensitive on Windows
+fn matches(pattern: &str, key: &str) -> bool {
+    #[cfg(windows)]
+    let (pattern, key) = (&pattern.to_uppercase(), &key.to_uppercase());
+
+    match pattern.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_modes() {
+        let policy = EnvPolicy::default();
+        assert!(policy.keeps("CARGO_HOME"));
+        assert!(!policy.keeps("ELECTRON_RUN_AS_NODE"));
+        assert!(!policy.keeps("NODE_OPTIONS"));
+
+        let policy = EnvPolicy { mode: EnvMode::Allowlist, ..EnvPolicy::default() };
+        assert!(policy.keeps("PATH"));
+        assert!(!policy.keeps("CARGO_HOME"));
+        #[cfg(not(windows))]
+        assert!(policy.keeps("LC_CTYPE"));
+
+        let policy = EnvPolicy { mode: EnvMode::Clean, ..EnvPolicy::default() };
+        assert!(!policy.keeps("PATH"));
+    }
+
+    #[test]
+    fn test_parse_env_output() {
+        let output = b"Welcome!\n__SW_LOGIN_ENV__\nPATH=/home/me/.cargo/bin:/usr/bin\0MULTI=a\nb\0SHLVL=2\0";
+        let vars = parse_env_output(output).unwrap();
+        assert_eq!(
+            vars,
+            [("PATH".to_string(), "/home/me/.cargo/bin:/usr/bin".to_string()), ("MULTI".to_string(), "a\nb".to_string())]
+        );
+        assert!(parse_env_output(b"zsh: command not found: env\n").is_none());
+
+        // Cut off by the timeout, the partial last entry is dropped
+        let output = b"__SW_LOGIN_ENV__\nPATH=/usr/local/bin:/usr/bin\0HOME=/ho";
+        assert_eq!(parse_env_output(output).unwrap(), [("PATH".to_string(), "/usr/local/bin:/usr/bin".to_string())]);
+    }
+}
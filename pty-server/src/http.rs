@@ -0,0 +1,184 @@
+// Minimal HTTP/1.1
+//
+// The WebSocket listener also answers plain GET requests (`/health`,
+// `/info`), and the metrics endpoint serves `/metrics`. Only the request head
+// is read; bodies are never needed. For WebSocket upgrades the bytes already
+// read are replayed to the handshake through `Replay`.
+use crate::osc::percent_decode;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Largest request head accepted
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// Headers parsed per request
+const MAX_HEADERS: usize = 64;
+
+/// Time a client gets to send its request head
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request line and headers
+#[derive(Debug)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    /// Header names are lowercase
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Decoded value of a query parameter, `+` is a space as in form encoding
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        let decode = |part: &str| percent_decode(&part.replace('+', " "));
+        self.query.as_deref()?.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            (decode(key) == name).then(|| decode(value))
+        })
+    }
+
+    /// `Upgrade: websocket`, the handshake itself is validated by tungstenite
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.header("upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+/// Read and parse a request head, returns it with every byte read so far
+pub async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<(RequestHead, Vec<u8>)> {
+    let read = async {
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 2048];
+        loop {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before request"));
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+            if let Some(head) = parse_head(&buffer)? {
+                return Ok((head, buffer));
+            }
+            if buffer.len() > MAX_HEAD_BYTES {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request head too large"));
+            }
+        }
+    };
+    tokio::time::timeout(HEAD_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request head timed out"))?
+}
+
+/// Parse a complete request head, `None` if more bytes are needed
+fn parse_head(buffer: &[u8]) -> io::Result<Option<RequestHead>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    let status = request.parse(buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if status.is_partial() {
+        return Ok(None);
+    }
+
+    let target = request.path.unwrap_or("/");
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+    Ok(Some(RequestHead {
+        method: request.method.unwrap_or_default().to_string(),
+        path: path.to_string(),
+        query,
+        headers: request
+            .headers
+            .iter()
+            .map(|h| (h.name.to_ascii_lowercase(), String::from_utf8_lossy(h.value).into_owned()))
+            .collect(),
+    }))
+}
+
+/// Write a complete response and close the connection
+pub async fn respond<S: AsyncWrite + Unpin>(stream: &mut S, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Stream that yields bytes already read before reading from `inner`
+pub struct Replay<S> {
+    buffered: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Replay<S> {
+    pub fn new(buffered: Vec<u8>, inner: S) -> Self {
+        Self { buffered, position: 0, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Replay<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.position < this.buffered.len() {
+            let n = buf.remaining().min(this.buffered.len() - this.position);
+            buf.put_slice(&this.buffered[this.position..this.position + n]);
+            this.position += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Replay<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_head() {
+        let request = b"GET /info?token=abc&x=1 HTTP/1.1\r\nHost: localhost\r\nUpgrade: WebSocket\r\n\r\n";
+        assert!(parse_head(&request[..20]).unwrap().is_none());
+
+        let head = parse_head(request).unwrap().unwrap();
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.path, "/info");
+        assert_eq!(head.query_param("token").as_deref(), Some("abc"));
+        assert_eq!(head.header("host"), Some("localhost"));
+        assert!(head.is_websocket_upgrade());
+
+        assert!(parse_head(b"NOT HTTP\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_query_param_decoding() {
+        // encodeURIComponent("a+b/c= d&%") and a namespace sent as form data
+        let request = b"GET /?token=a%2Bb%2Fc%3D%20d%26%25&namespace=my+vault%C3%A9 HTTP/1.1\r\n\r\n";
+        let head = parse_head(request).unwrap().unwrap();
+        assert_eq!(head.query_param("token").as_deref(), Some("a+b/c= d&%"));
+        assert_eq!(head.query_param("namespace").as_deref(), Some("my vaulté"));
+        assert_eq!(head.query_param("missing"), None);
+    }
+}
@@ -0,0 +1,111 @@
+// Shared Instance
+//
+// With `[instance] shared = true` (or `--shared`) one server runs per user
+// and every Obsidian window and vault connects to it, so sessions can move
+// between them. The first launch takes an exclusive lock on `instance.lock`
+// in the runtime directory and, once listening, writes its endpoint and token
+// to `instance.json` next to it. A later launch finds the lock taken, prints
+// the running instance's endpoint instead of starting, and exits. The lock is
+// released by the OS when the owner exits, so a crashed instance never blocks
+// the next one. Clients keep their sessions apart with namespaces.
+use crate::persist::write_private;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long a later launch waits for the owner to publish its endpoint
+const PUBLISH_WAIT: Duration = Duration::from_secs(5);
+
+/// Where clients reach the server, printed on startup and kept in `instance.json`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Endpoint {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket: Option<PathBuf>,
+    pub pid: u32,
+    /// Token of a shared instance, so later launches can hand it to their clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Set when a launch found this instance already running
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub existing: bool,
+    /// Listen address for logs
+    #[serde(skip)]
+    pub address: String,
+}
+
+/// Result of looking for a running instance
+pub enum Instance {
+    /// This process is the shared instance
+    Owner(Owner),
+    /// Another process is, with this endpoint
+    Running(Endpoint),
+}
+
+/// Lock held by the shared instance, removes `instance.json` when dropped
+pub struct Owner {
+    _lock: File,
+    info_path: PathBuf,
+}
+
+impl Owner {
+    /// Let later launches find this instance
+    pub fn publish(&self, endpoint: &Endpoint) -> io::Result<()> {
+        let json = serde_json::to_string(endpoint).map_err(io::Error::other)?;
+        write_private(&self.info_path, json.as_bytes())
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.info_path);
+    }
+}
+
+/// Become the shared instance, or find the one already running
+pub fn acquire(dir: &Path) -> io::Result<Instance> {
+    std::fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+
+    let info_path = dir.join("instance.json");
+    let lock = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(dir.join("instance.lock"))?;
+    match lock.try_lock() {
+        Ok(()) => {
+            // Left by an instance that didn't shut down cleanly
+            let _ = std::fs::remove_file(&info_path);
+            Ok(Instance::Owner(Owner { _lock: lock, info_path }))
+        }
+        Err(TryLockError::WouldBlock) => wait_for_endpoint(&info_path).map(Instance::Running),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// The owner publishes its endpoint once it listens, which may take a moment
+fn wait_for_endpoint(path: &Path) -> io::Result<Endpoint> {
+    let started = std::time::Instant::now();
+    loop {
+        if let Ok(text) = std::fs::read_to_string(path) {
+            if let Ok(endpoint) = serde_json::from_str::<Endpoint>(&text) {
+                return Ok(Endpoint { existing: true, ..endpoint });
+            }
+        }
+        if started.elapsed() >= PUBLISH_WAIT {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "running instance did not publish its endpoint"));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Random token for a shared instance started without one
+pub fn generate_token() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(io::Error::from)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
@@ -0,0 +1,339 @@
+// Shell Integration
+//
+// Integration scripts make the shell report its working directory through
+// OSC 7. They are loaded at startup through each shell's own mechanism, so
+// nothing is typed into the PTY, shown on screen or saved to history:
+//
+//   zsh     temporary ZDOTDIR whose rc files source the user's real ones
+//   bash    --rcfile sourcing ~/.bashrc, or the profile files for a login shell
+//   fish    --init-command
+//   nu      -e (runs after config.nu)
+//   pwsh    -NoExit -Command (runs after the profile)
+//   xonsh   --rc sourcing the default rc files
+//   elvish  -rc with the user's rc.elv appended
+//
+// Windows shells rely on frontend prompt parsing.
+use portable_pty::CommandBuilder;
+use std::path::PathBuf;
+#[cfg(not(windows))]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Bash ignores --rcfile in login mode, so login startup files are sourced here
+#[cfg(not(windows))]
+const BASH_RC: &str = r#"# Smart Workflow shell integration
+if [ -n "$__SW_BASH_LOGIN" ]; then
+    unset __SW_BASH_LOGIN
+    [ -r /etc/profile ] && . /etc/profile
+    for __sw_rc in ~/.bash_profile ~/.bash_login ~/.profile; do
+        [ -r "$__sw_rc" ] && { . "$__sw_rc"; break; }
+    done
+    unset __sw_rc
+else
+    [ -f ~/.bashrc ] && . ~/.bashrc
+fi
+__sw_cwd() { printf '\e]7;file://%s%s\e\\' "${HOSTNAME:-localhost}" "$PWD"; }
+PROMPT_COMMAND="__sw_cwd${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+"#;
+
+// Each zsh startup file restores the user's ZDOTDIR, sources the user's file
+// and switches back, so the next startup file is read from our directory too
+#[cfg(not(windows))]
+const ZSH_ZSHENV: &str = r#"# Smart Workflow shell integration
+ZDOTDIR="${__SW_USER_ZDOTDIR:-$HOME}"
+[[ -f "$ZDOTDIR/.zshenv" ]] && source "$ZDOTDIR/.zshenv"
+__SW_USER_ZDOTDIR="$ZDOTDIR"
+ZDOTDIR="$__SW_ZDOTDIR"
+"#;
+
+#[cfg(not(windows))]
+const ZSH_ZPROFILE: &str = r#"# Smart Workflow shell integration
+ZDOTDIR="$__SW_USER_ZDOTDIR"
+[[ -f "$ZDOTDIR/.zprofile" ]] && source "$ZDOTDIR/.zprofile"
+__SW_USER_ZDOTDIR="$ZDOTDIR"
+ZDOTDIR="$__SW_ZDOTDIR"
+"#;
+
+#[cfg(not(windows))]
+const ZSH_ZSHRC: &str = r#"# Smart Workflow shell integration
+ZDOTDIR="$__SW_USER_ZDOTDIR"
+[[ -f "$ZDOTDIR/.zshrc" ]] && source "$ZDOTDIR/.zshrc"
+__SW_USER_ZDOTDIR="$ZDOTDIR"
+__sw_cwd() { printf '\e]7;file://%s%s\e\\' "${HOST:-localhost}" "$PWD" }
+autoload -Uz add-zsh-hook
+add-zsh-hook precmd __sw_cwd
+add-zsh-hook chpwd __sw_cwd
+if [[ -o login ]]; then
+    ZDOTDIR="$__SW_ZDOTDIR"
+else
+    unset __SW_USER_ZDOTDIR __SW_ZDOTDIR
+fi
+"#;
+
+#[cfg(not(windows))]
+const ZSH_ZLOGIN: &str = r#"# Smart Workflow shell integration
+ZDOTDIR="$__SW_USER_ZDOTDIR"
+[[ -f "$ZDOTDIR/.zlogin" ]] && source "$ZDOTDIR/.zlogin"
+unset __SW_USER_ZDOTDIR __SW_ZDOTDIR
+"#;
+
+#[cfg(not(windows))]
+const FISH_INIT: &str = r#"# Smart Workflow shell integration
+function __sw_cwd --on-variable PWD
+    printf '\e]7;file://%s%s\e\\' (hostname) $PWD
+end
+__sw_cwd
+"#;
+
+#[cfg(not(windows))]
+const NU_INIT: &str = r#"# Smart Workflow shell integration
+$env.config.hooks.pre_prompt = ($env.config.hooks.pre_prompt? | default [] | append {|| print -n $"\e]7;file://localhost($env.PWD)\e\\" })
+"#;
+
+#[cfg(not(windows))]
+const PWSH_INIT: &str = r#"# Smart Workflow shell integration
+function global:__sw_cwd {
+    $l = $executionContext.SessionState.Path.CurrentLocation
+    if ($l.Provider.Name -eq 'FileSystem') { [Console]::Write("`e]7;file://localhost$($l.ProviderPath)`e\") }
+}
+$global:__sw_prompt = $function:prompt
+function global:prompt { __sw_cwd; & $global:__sw_prompt }
+"#;
+
+#[cfg(not(windows))]
+const XONSH_RC: &str = r#"# Smart Workflow shell integration
+import os as __sw_os
+for __sw_rc in ['/etc/xonsh/xonshrc', __sw_os.path.expanduser('~/.config/xonsh/rc.xsh'), __sw_os.path.expanduser('~/.xonshrc')]:
+    if __sw_os.path.isfile(__sw_rc):
+        source @(__sw_rc)
+
+def __sw_cwd(**kw):
+    print('\x1b]7;file://localhost' + __xonsh__.env['PWD'] + '\x1b\\', end='', flush=True)
+
+events.on_chdir(__sw_cwd)
+__sw_cwd()
+"#;
+
+// The user's rc.elv is appended, so its imports and definitions stay in the REPL namespace
+#[cfg(not(windows))]
+const ELVISH_RC: &str = r#"# Smart Workflow shell integration
+set edit:before-readline = [$@edit:before-readline { print "\e]7;file://localhost"$pwd"\e\\" }]
+"#;
+
+/// Shells with integration support, keyed by executable name
+const SUPPORTED_SHELLS: &[&str] = &["bash", "zsh", "fish", "nu", "pwsh", "xonsh", "elvish"];
+
+#[cfg(not(windows))]
+static NEXT_DIR_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Integration prepared for one session, its files are removed on drop
+pub struct ShellIntegration {
+    dir: PathBuf,
+}
+
+impl Drop for ShellIntegration {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Shell kind from the command's executable name (bash, zsh, ...)
+pub fn shell_kind(cmd: &CommandBuilder) -> Option<&'static str> {
+    let name = crate::shell::program_name(cmd)?;
+    SUPPORTED_SHELLS.iter().copied().find(|&kind| kind == name)
+}
+
+/// Whether a shell type supports integration on this platform
+pub fn is_supported(shell_type: &str) -> bool {
+    cfg!(not(windows)) && SUPPORTED_SHELLS.contains(&shell_type)
+}
+
+/// Set up integration for the shell about to be spawned by `cmd`
+#[cfg(not(windows))]
+pub fn prepare(cmd: &mut CommandBuilder) -> std::io::Result<Option<ShellIntegration>> {
+    let Some(kind) = shell_kind(cmd) else {
+        return Ok(None);
+    };
+
+    if kind == "bash" {
+        // A login shell ignores --rcfile, the rc file sources the login files instead
+        let argv = cmd.get_argv_mut();
+        let before = argv.len();
+        argv.retain(|arg| arg != "-l" && arg != "--login");
+        if argv.len() < before {
+            cmd.env("__SW_BASH_LOGIN", "1");
+        }
+    }
+
+    let dir = create_dir()?;
+    // Owns the directory from here on, so it is removed if writing fails
+    let integration = ShellIntegration { dir: dir.clone() };
+    let file = |name: &str, content: &str| -> std::io::Result<String> {
+        let path = dir.join(name);
+        std::fs::write(&path, content)?;
+        Ok(path.to_string_lossy().into_owned())
+    };
+
+    match kind {
+        "bash" => {
+            let rc = file("bashrc", BASH_RC)?;
+            insert_args(cmd, &["--rcfile", &rc]);
+        }
+        "zsh" => {
+            file(".zshenv", ZSH_ZSHENV)?;
+            file(".zprofile", ZSH_ZPROFILE)?;
+            file(".zshrc", ZSH_ZSHRC)?;
+            file(".zlogin", ZSH_ZLOGIN)?;
+            if let Some(user_zdotdir) = cmd.get_env("ZDOTDIR").map(|v| v.to_os_string()) {
+                cmd.env("__SW_USER_ZDOTDIR", user_zdotdir);
+            }
+            cmd.env("__SW_ZDOTDIR", &dir);
+            cmd.env("ZDOTDIR", &dir);
+        }
+        "fish" => {
+            let init = file("integration.fish", FISH_INIT)?;
+            insert_args(cmd, &["--init-command", &format!("source {}", single_quote(&init, "\\'"))]);
+        }
+        "nu" => {
+            let init = file("integration.nu", NU_INIT)?;
+            insert_args(cmd, &["-e", &format!("source {}", single_quote(&init, "''"))]);
+        }
+        "pwsh" => {
+            // -Command consumes the rest of the command line, so it goes last
+            let init = file("integration.ps1", PWSH_INIT)?;
+            cmd.args(["-NoExit", "-Command", &format!(". {}", single_quote(&init, "''"))]);
+        }
+        "xonsh" => {
+            let rc = file("rc.xsh", XONSH_RC)?;
+            insert_args(cmd, &[&format!("--rc={}", rc)]);
+        }
+        "elvish" => {
+            // -rc replaces the user's rc file, so its content follows the integration
+            let mut content = ELVISH_RC.to_string();
+            if let Some(user_rc) = elvish_user_rc(cmd).and_then(|path| std::fs::read_to_string(path).ok()) {
+                content.push_str(&user_rc);
+            }
+            let rc = file("rc.elv", &content)?;
+            insert_args(cmd, &["-rc", &rc]);
+        }
+        _ => return Ok(None),
+    }
+
+    Ok(Some(integration))
+}
+
+/// Windows shells don't use Shell Integration, rely on frontend prompt parsing
+#[cfg(windows)]
+pub fn prepare(_cmd: &mut CommandBuilder) -> std::io::Result<Option<ShellIntegration>> {
+    Ok(None)
+}
+
+/// Create a private directory for the session's integration files
+#[cfg(not(windows))]
+fn create_dir() -> std::io::Result<PathBuf> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let id = NEXT_DIR_ID.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("pty-server-{}-{}", std::process::id(), id));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// Elvish's own rc file: `$XDG_CONFIG_HOME/elvish/rc.elv`, or the legacy `~/.elvish/rc.elv`
+#[cfg(not(windows))]
+fn elvish_user_rc(cmd: &CommandBuilder) -> Option<PathBuf> {
+    let var = |name: &str| cmd.get_env(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+    let home = var("HOME");
+    let config = var("XDG_CONFIG_HOME").or_else(|| home.as_ref().map(|home| home.join(".config")));
+    [config.map(|dir| dir.join("elvish/rc.elv")), home.map(|home| home.join(".elvish/rc.elv"))]
+        .into_iter()
+        .flatten()
+        .find(|path| path.is_file())
+}
+
+/// Insert arguments right after the program, before user arguments
+#[cfg(not(windows))]
+fn insert_args(cmd: &mut CommandBuilder, args: &[&str]) {
+    let argv = cmd.get_argv_mut();
+    for (i, arg) in args.iter().enumerate() {
+        argv.insert(1 + i, arg.into());
+    }
+}
+
+/// Wrap a path in single quotes, escaping embedded quotes shell-style
+#[cfg(not(windows))]
+fn single_quote(path: &str, escaped_quote: &str) -> String {
+    format!("'{}'", path.replace('\'', escaped_quote))
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+
+    #[test]
+    fn test_scripts_emit_osc7() {
+        for script in [BASH_RC, ZSH_ZSHRC, FISH_INIT, NU_INIT, PWSH_INIT, XONSH_RC, ELVISH_RC] {
+            assert!(script.contains("]7;file://"), "{}", script);
+        }
+    }
+
+    #[test]
+    fn test_zsh_uses_temporary_zdotdir() {
+        let mut cmd = CommandBuilder::new("/bin/zsh");
+        let integration = prepare(&mut cmd).unwrap().unwrap();
+        let dir = PathBuf::from(cmd.get_env("ZDOTDIR").unwrap());
+        assert!(dir.join(".zshrc").is_file());
+        assert_eq!(cmd.get_argv().len(), 1);
+
+        drop(integration);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_bash_rcfile_precedes_user_args() {
+        let mut cmd = CommandBuilder::new("bash");
+        cmd.arg("-i");
+        let _integration = prepare(&mut cmd).unwrap().unwrap();
+        let argv: Vec<_> = cmd.get_argv().iter().map(|a| a.to_string_lossy().into_owned()).collect();
+        assert_eq!(argv[1], "--rcfile");
+        assert!(argv[2].ends_with("bashrc"));
+        assert_eq!(argv[3], "-i");
+    }
+
+    #[test]
+    fn test_bash_login_sourced_from_rcfile() {
+        let mut cmd = CommandBuilder::new("bash");
+        cmd.args(["-l", "-i"]);
+        let _integration = prepare(&mut cmd).unwrap().unwrap();
+        assert_eq!(cmd.get_argv().len(), 4);
+        assert_eq!(cmd.get_env("__SW_BASH_LOGIN").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_bash_user_login_sourced_from_rcfile() {
+        // Login flags from launch mode and from shell_args alike
+        let mut cmd = CommandBuilder::new("bash");
+        cmd.args(["-l", "--login"]);
+        let _integration = prepare(&mut cmd).unwrap().unwrap();
+        assert_eq!(cmd.get_argv().len(), 3);
+        assert_eq!(cmd.get_argv()[1], "--rcfile");
+        assert_eq!(cmd.get_env("__SW_BASH_LOGIN").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_elvish_rc_includes_user_rc() {
+        let home = TempDir::new("elvish-home");
+        std::fs::create_dir_all(home.join(".config/elvish")).unwrap();
+        std::fs::write(home.join(".config/elvish/rc.elv"), "use str\n").unwrap();
+
+        let mut cmd = CommandBuilder::new("elvish");
+        cmd.env("HOME", &*home);
+        cmd.env_remove("XDG_CONFIG_HOME");
+        let _integration = prepare(&mut cmd).unwrap().unwrap();
+        let argv = cmd.get_argv();
+        assert_eq!(argv[1], "-rc");
+        let rc = std::fs::read_to_string(&argv[2]).unwrap();
+        assert!(rc.starts_with(ELVISH_RC) && rc.ends_with("use str\n"));
+    }
+}
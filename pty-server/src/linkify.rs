@@ -0,0 +1,289 @@
+// Vault Path Hyperlinking
+//
+// Optional output transformer wrapping file paths that point into the vault
+// (e.g. `notes/foo.md:12` printed by grep or a compiler) in OSC 8 hyperlinks
+// to `obsidian://open`. Escape sequences pass through untouched and existing
+// OSC 8 links are left alone. Looking paths up touches the filesystem, so the
+// read task runs the transformer off the runtime thread.
+use std::path::{Path, PathBuf};
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Longest token considered a path candidate
+const MAX_TOKEN_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Plain text, path tokens are collected here
+    Ground,
+    /// After ESC
+    Escape,
+    /// Inside CSI sequence, ends with a final byte 0x40..=0x7e
+    Csi,
+    /// Inside OSC/DCS/... string, ends with BEL or ST
+    Str,
+    /// After ESC inside a string
+    StrEscape,
+}
+
+/// Streaming hyperlink transformer
+pub struct Linkifier {
+    vault_root: PathBuf,
+    state: State,
+    /// Current path candidate in plain text
+    token: Vec<u8>,
+    /// Payload of the current OSC string, to follow OSC 8 links from programs
+    osc: Vec<u8>,
+    /// Inside a hyperlink emitted by the program itself
+    in_program_link: bool,
+}
+
+impl Linkifier {
+    /// Create a transformer for a vault, returns None if the root doesn't exist
+    pub fn new(vault_root: &str) -> Option<Self> {
+        Some(Self {
+            vault_root: std::fs::canonicalize(vault_root).ok()?,
+            state: State::Ground,
+            token: Vec::new(),
+            osc: Vec::new(),
+            in_program_link: false,
+        })
+    }
+
+    /// Transform a chunk of output, resolving relative paths against `cwd`.
+    /// A trailing token may continue in the next chunk and is held back until
+    /// then, or until `flush`.
+    pub fn transform(&mut self, data: &[u8], cwd: Option<&str>) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+
+        for &byte in data {
+            match self.state {
+                State::Ground => {
+                    if is_token_byte(byte) && self.token.len() < MAX_TOKEN_LEN {
+                        self.token.push(byte);
+                        continue;
+                    }
+                    self.flush_token(&mut out, cwd);
+                    if byte == ESC {
+                        self.state = State::Escape;
+                    }
+                    out.push(byte);
+                }
+                State::Escape => {
+                    out.push(byte);
+                    self.state = match byte {
+                        b'[' => State::Csi,
+                        b']' => {
+                            self.osc.clear();
+                            State::Str
+                        }
+                        b'P' | b'X' | b'^' | b'_' => {
+                            self.osc.clear();
+                            State::Str
+                        }
+                        ESC => State::Escape,
+                        _ => State::Ground,
+                    };
+                }
+                State::Csi => {
+                    out.push(byte);
+                    if (0x40..=0x7e).contains(&byte) {
+                        self.state = State::Ground;
+                    }
+                }
+                State::Str => {
+                    out.push(byte);
+                    match byte {
+                        BEL => self.end_string(),
+                        ESC => self.state = State::StrEscape,
+                        _ if self.osc.len() < MAX_TOKEN_LEN => self.osc.push(byte),
+                        _ => {}
+                    }
+                }
+                State::StrEscape => {
+                    out.push(byte);
+                    if byte == b'\\' {
+                        self.end_string();
+                    } else {
+                        self.state = State::Str;
+                    }
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Whether a token is held back for the next chunk
+    pub fn has_pending(&self) -> bool {
+        !self.token.is_empty()
+    }
+
+    /// Output the held back token, once the program paused or exited
+    pub fn flush(&mut self, cwd: Option<&str>) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.flush_token(&mut out, cwd);
+        out
+    }
+
+    fn end_string(&mut self) {
+        // OSC 8 ; params ; URI — an empty URI closes the link
+        if let Some(rest) = self.osc.strip_prefix(b"8;") {
+            let uri = rest.splitn(2, |&b| b == b';').nth(1).unwrap_or(b"");
+            self.in_program_link = !uri.is_empty();
+        }
+        self.osc.clear();
+        self.state = State::Ground;
+    }
+
+    fn flush_token(&mut self, out: &mut Vec<u8>, cwd: Option<&str>) {
+        if self.token.is_empty() {
+            return;
+        }
+
+        let token = std::mem::take(&mut self.token);
+        match self.link_for(&token, cwd) {
+            Some((uri, len)) => {
+                out.extend_from_slice(b"\x1b]8;;");
+                out.extend_from_slice(uri.as_bytes());
+                out.extend_from_slice(b"\x1b\\");
+                out.extend_from_slice(&token[..len]);
+                out.extend_from_slice(b"\x1b]8;;\x1b\\");
+                out.extend_from_slice(&token[len..]);
+            }
+            None => out.extend_from_slice(&token),
+        }
+    }
+
+    /// Resolve a token to an `obsidian://open` URI, returns (uri, linked length)
+    fn link_for(&self, token: &[u8], cwd: Option<&str>) -> Option<(String, usize)> {
+        if self.in_program_link {
+            return None;
+        }
+        let text = std::str::from_utf8(token).ok()?;
+
+        let (path, linked_len) = split_location(text);
+        if !has_extension(path) {
+            return None;
+        }
+
+        let candidate = Path::new(path);
+        let full = if candidate.is_absolute() {
+            candidate.to_path_buf()
+        } else {
+            Path::new(cwd?).join(candidate)
+        };
+
+        let resolved = std::fs::canonicalize(full).ok()?;
+        if !resolved.starts_with(&self.vault_root) || !resolved.is_file() {
+            return None;
+        }
+
+        let uri = format!("obsidian://open?path={}", uri_encode(&resolved.to_string_lossy()));
+        Some((uri, linked_len))
+    }
+}
+
+/// Bytes that may appear in a path token, non-ASCII is kept intact
+fn is_token_byte(byte: u8) -> bool {
+    byte >= 0x80
+        || byte.is_ascii_alphanumeric()
+        || matches!(byte, b'/' | b'\\' | b'.' | b'_' | b'-' | b'~' | b':' | b'+' | b'@' | b'%' | b'#')
+}
+
+/// Split `path[:line[:col]]` off the start of a token (grep, rustc, tsc style),
+/// returns the path and the length of the linked text
+fn split_location(text: &str) -> (&str, usize) {
+    let bytes = text.as_bytes();
+    let digits_at = |i: usize| bytes[i..].iter().take_while(|b| b.is_ascii_digit()).count();
+
+    // Skip the colon of a Windows drive letter
+    let start = if bytes.len() > 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' { 2 } else { 0 };
+
+    for (i, &byte) in bytes.iter().enumerate().skip(start) {
+        if byte != b':' {
+            continue;
+        }
+        let line = digits_at(i + 1);
+        if line == 0 {
+            continue;
+        }
+        let mut len = i + 1 + line;
+        if bytes.get(len) == Some(&b':') {
+            let col = digits_at(len + 1);
+            if col > 0 {
+                len += 1 + col;
+            }
+        }
+        return (&text[..i], len);
+    }
+
+    // Trailing punctuation is part of the sentence, not the path
+    let path = text.trim_end_matches(['.', ',', ':', ';', '!', '?']);
+    (path, path.len())
+}
+
+/// Only tokens with a file extension are looked up on disk
+fn has_extension(path: &str) -> bool {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    matches!(name.rfind('.'), Some(i) if i > 0 && i + 1 < name.len())
+}
+
+/// Percent-encode everything but unreserved characters and path separators
+fn uri_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~' | b'/') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+
+    fn vault(name: &str) -> TempDir {
+        let dir = TempDir::new(name);
+        std::fs::create_dir_all(dir.join("notes")).unwrap();
+        std::fs::write(dir.join("notes/日记.md"), "").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_links_vault_path_with_line_number() {
+        let root = vault("linkify-line");
+        let mut linkifier = Linkifier::new(root.to_str().unwrap()).unwrap();
+        let cwd = root.to_str();
+
+        let out = linkifier.transform("\x1b[35mnotes/日记.md:12\x1b[m: todo\n".as_bytes(), cwd);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\x1b[35m\x1b]8;;obsidian://open?path="));
+        assert!(out.contains("%E6%97%A5%E8%AE%B0.md\x1b\\notes/日记.md:12\x1b]8;;\x1b\\\x1b[m: todo"));
+
+        let out = linkifier.transform("notes/日记.md:3:5:match\n".as_bytes(), cwd);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.ends_with("\x1b\\notes/日记.md:3:5\x1b]8;;\x1b\\:match\n"));
+    }
+
+    #[test]
+    fn test_token_split_across_chunks() {
+        let root = vault("linkify-split");
+        let mut linkifier = Linkifier::new(root.to_str().unwrap()).unwrap();
+        let cwd = root.to_str();
+
+        let first = linkifier.transform(&"see notes/日".as_bytes()[..12], cwd);
+        assert_eq!(first, b"see ");
+        assert!(linkifier.has_pending());
+        let second = linkifier.transform(&"see notes/日记.md missing.md".as_bytes()[12..], cwd);
+        let second = String::from_utf8(second).unwrap();
+        assert!(second.starts_with("\x1b]8;;obsidian://open?path="));
+        assert!(second.ends_with("notes/日记.md\x1b]8;;\x1b\\ "));
+        assert_eq!(linkifier.flush(cwd), b"missing.md");
+    }
+}
@@ -0,0 +1,294 @@
+// Logging
+//
+// Log lines go to stderr, stdout only carries the startup JSON read by the
+// plugin. Lines are plain text or JSON, and can additionally be appended to
+// a log file that is rotated by size, so users can attach it to bug reports.
+// The level is checked at runtime, debug messages are available in release
+// builds too.
+use crate::config::LogSettings;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Log at a level, optionally with a session context:
+/// `log_at!(Level::Info, ctx: log_ctx, "Session closed")`
+macro_rules! log_at {
+    ($level:expr, ctx: $ctx:expr, $($arg:tt)+) => {
+        $crate::logging::write($level, Some(&$ctx), format_args!($($arg)+))
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::logging::write($level, None, format_args!($($arg)+))
+    };
+}
+
+macro_rules! log_error {
+    ($($arg:tt)+) => { log_at!($crate::logging::Level::Error, $($arg)+) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)+) => { log_at!($crate::logging::Level::Warn, $($arg)+) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)+) => { log_at!($crate::logging::Level::Info, $($arg)+) };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)+) => { log_at!($crate::logging::Level::Debug, $($arg)+) };
+}
+
+/// Log level, each level includes the ones above it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            Level::Debug
+        } else {
+            Level::Info
+        }
+    }
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/// Line format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// `[INFO] message`, with a timestamp in the log file
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Session a log line belongs to
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Context {
+    pub session: Option<u64>,
+    pub pid: Option<u32>,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(if cfg!(debug_assertions) { Level::Debug as u8 } else { Level::Info as u8 });
+static COLOR: AtomicBool = AtomicBool::new(false);
+static JSON: AtomicBool = AtomicBool::new(false);
+static FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+/// Log file rotated once it would grow past `max_bytes`
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    /// Rotated files kept as `<path>.1` (newest) to `<path>.<max_files>`
+    max_files: usize,
+}
+
+impl LogFile {
+    fn open(path: &Path, max_bytes: u64, max_files: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), file, size, max_bytes, max_files })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                let _ = std::fs::rename(self.rotated(i), self.rotated(i + 1));
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+}
+
+/// Apply logging settings, colors are only used when stderr is a terminal
+pub fn init(settings: &LogSettings) -> std::io::Result<()> {
+    set_level(settings.level);
+    COLOR.store(settings.color && std::io::stderr().is_terminal(), Ordering::Relaxed);
+    JSON.store(settings.format == Format::Json, Ordering::Relaxed);
+
+    let file = match &settings.file {
+        Some(path) => Some(LogFile::open(path, settings.max_file_bytes, settings.max_files)?),
+        None => None,
+    };
+    *FILE.lock().unwrap() = file;
+    Ok(())
+}
+
+/// Change the level while running
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether messages of this level are written
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Write a log line if its level is enabled, use the `log_*!` macros instead
+pub fn write(level: Level, context: Option<&Context>, args: std::fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    let time = timestamp(SystemTime::now());
+    let message = args.to_string();
+    let context = context.copied().unwrap_or_default();
+
+    if JSON.load(Ordering::Relaxed) {
+        let line = json_line(&time, level, &context, &message);
+        eprintln!("{}", line);
+        write_file(&line);
+        return;
+    }
+
+    let label = level.name().to_uppercase();
+    let prefix = match (context.session, context.pid) {
+        (Some(session), Some(pid)) => format!("(session {}, pid {}) ", session, pid),
+        (Some(session), None) => format!("(session {}) ", session),
+        (None, Some(pid)) => format!("(pid {}) ", pid),
+        (None, None) => String::new(),
+    };
+    if COLOR.load(Ordering::Relaxed) {
+        let color = match level {
+            Level::Error => "31",
+            Level::Warn => "33",
+            Level::Info => "32",
+            Level::Debug => "34",
+        };
+        eprintln!("\x1b[{}m[{}]\x1b[0m {}{}", color, label, prefix, message);
+    } else {
+        eprintln!("[{}] {}{}", label, prefix, message);
+    }
+    write_file(&format!("{} [{}] {}{}", time, label, prefix, message));
+}
+
+fn write_file(line: &str) {
+    let mut file = FILE.lock().unwrap();
+    if let Some(log_file) = file.as_mut() {
+        if let Err(e) = log_file.write_line(line) {
+            // Keep logging to stderr, a full disk shouldn't take the server down
+            eprintln!("[ERROR] Cannot write log file {}: {}", log_file.path.display(), e);
+            *file = None;
+        }
+    }
+}
+
+fn json_line(time: &str, level: Level, context: &Context, message: &str) -> String {
+    let mut line = serde_json::Map::new();
+    line.insert("time".to_string(), time.into());
+    line.insert("level".to_string(), level.name().into());
+    if let Some(session) = context.session {
+        line.insert("session".to_string(), session.into());
+    }
+    if let Some(pid) = context.pid {
+        line.insert("pid".to_string(), pid.into());
+    }
+    line.insert("message".to_string(), message.into());
+    serde_json::Value::Object(line).to_string()
+}
+
+/// RFC 3339 UTC timestamp with milliseconds
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(timestamp(time), "2024-02-29T12:34:56.789Z");
+    }
+
+    #[test]
+    fn test_json_line() {
+        let context = Context { session: Some(3), pid: None };
+        let line = json_line("t", Level::Warn, &context, "say \"hi\"");
+        assert_eq!(line, r#"{"level":"warn","message":"say \"hi\"","session":3,"time":"t"}"#);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = TempDir::new("log");
+        let path = dir.join("server.log");
+
+        let mut log_file = LogFile::open(&path, 10, 2).unwrap();
+        for line in ["first", "second", "third", "fourth"] {
+            log_file.write_line(line).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(std::fs::read_to_string(dir.join("server.log.1")).unwrap(), "third\n");
+        assert_eq!(std::fs::read_to_string(dir.join("server.log.2")).unwrap(), "second\n");
+        assert!(!dir.join("server.log.3").exists());
+    }
+}
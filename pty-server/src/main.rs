@@ -1,78 +1,337 @@
 // PTY Server Main Program
+#[macro_use]
+mod logging;
 mod server;
 mod pty_session;
 mod shell;
+mod osc;
+mod sessions;
+mod process;
+mod linkify;
+mod integration;
+mod environment;
+mod config;
+mod http;
+mod metrics;
+mod web;
+mod shared;
+mod persist;
+mod instance;
+mod utf8;
+mod transcode;
+mod paste;
+#[cfg(test)]
+mod test_dir;
 
+use clap::Parser;
+use config::{Config, Transport};
+use environment::{BaseEnv, BaseEnvCache};
+use instance::Instance;
+use logging::{Format, Level};
 use server::{Server, ServerConfig};
-use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
 
-/// Logging macro
-macro_rules! log_info {
-    ($($arg:tt)*) => {
-        eprintln!("[INFO] {}", format!($($arg)*));
-    };
+/// PTY server for the Smart Workflow terminal.
+/// Flags override the config file, which overrides the defaults.
+#[derive(Parser, Debug, Clone)]
+#[command(name = "pty-server", version, about)]
+struct Cli {
+    /// Config file [default: $XDG_CONFIG_HOME/pty-server/config.toml]
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Listen port, 0 picks a random port
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Listen address
+    #[arg(short, long, value_name = "ADDR")]
+    bind: Option<IpAddr>,
+
+    /// Serve WebSocket over TCP or a Unix domain socket
+    #[arg(long, value_enum)]
+    transport: Option<Transport>,
+
+    /// Socket path for the unix transport
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+
+    /// Token clients must send (prefer --token-file, arguments are visible to other users)
+    #[arg(long)]
+    token: Option<String>,
+
+    /// File holding the token clients must send
+    #[arg(long, value_name = "FILE")]
+    token_file: Option<PathBuf>,
+
+    /// Sessions running at the same time
+    #[arg(long, value_name = "N")]
+    max_sessions: Option<usize>,
+
+    /// Largest WebSocket message accepted from clients
+    #[arg(long, value_name = "BYTES")]
+    max_message_bytes: Option<usize>,
+
+    /// Terminal columns until the client resizes
+    #[arg(long)]
+    cols: Option<u16>,
+
+    /// Terminal rows until the client resizes
+    #[arg(long)]
+    rows: Option<u16>,
+
+    /// Size of a single PTY read
+    #[arg(long, value_name = "BYTES")]
+    pty_read_buffer: Option<usize>,
+
+    /// Log level
+    #[arg(long, value_enum)]
+    log_level: Option<Level>,
+
+    /// Log line format
+    #[arg(long, value_enum)]
+    log_format: Option<Format>,
+
+    /// Also append logs to this file, rotated by size
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Disable colored logs
+    #[arg(long)]
+    no_color: bool,
+
+    /// Serve Prometheus metrics on this local address, e.g. 127.0.0.1:9464
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Serve the browser terminal at /terminal (requires a token)
+    #[arg(long)]
+    web: bool,
+
+    /// Run one server per user, later launches print its endpoint and exit
+    #[arg(long)]
+    shared: bool,
+
+    /// Save sessions so they can be restored after a restart
+    #[arg(long)]
+    persist: bool,
+
+    /// Use the login shell's environment as the session base
+    #[arg(long)]
+    login_env: bool,
+
+    /// Print installed shells as JSON and exit
+    #[arg(long)]
+    list_shells: bool,
+
+    /// Print configured profiles as JSON and exit
+    #[arg(long)]
+    list_profiles: bool,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    print_config: bool,
 }
 
-macro_rules! log_debug {
-    ($($arg:tt)*) => {
-        if cfg!(debug_assertions) {
-            eprintln!("[DEBUG] {}", format!($($arg)*));
+impl Cli {
+    /// Copy of the arguments that is safe to log
+    fn redacted(&self) -> Cli {
+        let token = self.token.as_ref().map(|_| "<redacted>".to_string());
+        Cli { token, ..self.clone() }
+    }
+
+    /// Apply flags on top of the config file
+    fn apply(&self, config: &mut Config) {
+        let server = &mut config.server;
+        if let Some(port) = self.port {
+            server.port = port;
         }
-    };
-}
+        if let Some(bind) = self.bind {
+            server.bind = bind;
+        }
+        if let Some(transport) = self.transport {
+            server.transport = transport;
+        }
+        if let Some(socket) = &self.socket {
+            server.socket = Some(socket.clone());
+        }
+        server.login_env |= self.login_env;
+        config.web.enabled |= self.web;
+        config.persistence.enabled |= self.persist;
+        config.instance.shared |= self.shared;
 
-/// Parse command line arguments
-fn parse_args() -> u16 {
-    let args: Vec<String> = env::args().collect();
-    let mut port: u16 = 0;
-    
-    let mut i = 1;
-    while i < args.len() {
-        match args[i].as_str() {
-            "-p" | "--port" => {
-                if i + 1 < args.len() {
-                    port = args[i + 1].parse().unwrap_or(0);
-                    i += 1;
-                }
-            }
-            arg if arg.starts_with("--port=") => {
-                port = arg.trim_start_matches("--port=").parse().unwrap_or(0);
-            }
-            "-h" | "--help" => {
-                eprintln!("Usage: pty-server [OPTIONS]");
-                eprintln!("Options:");
-                eprintln!("  -p, --port <PORT>  Listen port (0 for random port) [default: 0]");
-                eprintln!("  -h, --help         Show help information");
-                std::process::exit(0);
-            }
-            _ => {}
+        if let Some(token) = &self.token {
+            config.auth.token = Some(token.clone());
+            config.auth.token_file = None;
+        }
+        if let Some(token_file) = &self.token_file {
+            config.auth.token_file = Some(token_file.clone());
+        }
+        if let Some(max_sessions) = self.max_sessions {
+            config.limits.max_sessions = Some(max_sessions);
+        }
+        if let Some(max_message_bytes) = self.max_message_bytes {
+            config.limits.max_message_bytes = max_message_bytes;
+        }
+        if let Some(cols) = self.cols {
+            config.terminal.cols = cols;
+        }
+        if let Some(rows) = self.rows {
+            config.terminal.rows = rows;
+        }
+        if let Some(pty_read) = self.pty_read_buffer {
+            config.buffers.pty_read = pty_read;
+        }
+        if let Some(level) = self.log_level {
+            config.log.level = level;
+        }
+        if let Some(format) = self.log_format {
+            config.log.format = format;
+        }
+        if let Some(file) = &self.log_file {
+            config.log.file = Some(file.clone());
+        }
+        if let Some(listen) = self.metrics_listen {
+            config.metrics.listen = Some(listen);
+        }
+        if self.no_color {
+            config.log.color = false;
         }
-        i += 1;
     }
-    
-    port
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Parse command line arguments
-    let port = parse_args();
+    // Parse command line arguments, invalid values exit with a usage error
+    let cli = Cli::parse();
+
+    if cli.list_shells {
+        let shells = shell::discover_shells();
+        println!("{}", serde_json::to_string_pretty(&shells).unwrap_or_default());
+        return Ok(());
+    }
 
-    log_debug!("Startup args: port={}", port);
+    // A broken config file or invalid setting is a startup error
+    let settings = Config::load(cli.config.as_deref()).and_then(|mut settings| {
+        cli.apply(&mut settings);
+        settings.finish()?;
+        Ok(settings)
+    });
+    let mut settings = match settings {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = logging::init(&settings.log) {
+        eprintln!("error: Cannot open log file: {}", e);
+        std::process::exit(2);
+    }
+    log_debug!("Startup args: {:?}", cli.redacted());
+
+    if cli.print_config {
+        print!("{}", settings.to_redacted_toml());
+        return Ok(());
+    }
+    if cli.list_profiles {
+        println!("{}", serde_json::to_string_pretty(&settings.profile_entries()).unwrap_or_default());
+        return Ok(());
+    }
+
+    // With a shared instance, a later launch only reports the running one
+    let instance = match settings.instance.runtime_dir.as_deref().filter(|_| settings.instance.shared) {
+        Some(dir) => match instance::acquire(dir) {
+            Ok(Instance::Owner(owner)) => Some(owner),
+            Ok(Instance::Running(endpoint)) => {
+                log_info!("Shared instance already running, pid {}", endpoint.pid);
+                println!("{}", serde_json::to_string(&endpoint)?);
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("error: Cannot check for a running instance in {}: {}", dir.display(), e);
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
+    if instance.is_some() && settings.auth.token.is_none() {
+        match instance::generate_token() {
+            Ok(token) => settings.auth.token = Some(token),
+            Err(e) => {
+                eprintln!("error: Cannot generate a token for the shared instance: {}", e);
+                std::process::exit(2);
+            }
+        }
+    }
 
     // Create server config
-    let config = ServerConfig { port };
+    let login_env = settings.server.login_env;
+    let metrics_listen = settings.metrics.listen;
+    let store = persist::SessionStore::load(&settings.persistence).map(Arc::new);
+    let base_env = Arc::new(BaseEnvCache::new(login_env));
+    let config = ServerConfig {
+        settings: Arc::new(settings),
+        started: std::time::Instant::now(),
+        base_env: Arc::clone(&base_env),
+        store,
+    };
 
     // Create and start server
     let server = Server::new(config);
-    let port = server.start().await?;
+    let endpoint = server.start().await?;
+    if let Some(owner) = &instance {
+        if let Err(e) = owner.publish(&endpoint) {
+            log_warn!("Cannot publish the shared instance: {}", e);
+        }
+    }
+
+    if let Some(listen) = metrics_listen {
+        let metrics_address = metrics::serve(listen).await?;
+        log_info!("Metrics available at http://{}/metrics", metrics_address);
+    }
+
+    // Capture the login environment now instead of delaying the first session
+    if login_env {
+        tokio::spawn(async move {
+            match base_env.get().await {
+                BaseEnv::Login(vars) => {
+                    log_info!("Captured login environment ({} variables)", vars.len());
+                }
+                BaseEnv::LoginFailed(e) => {
+                    log_warn!("Login environment unavailable, using the server's: {}", e);
+                }
+                BaseEnv::Server => {}
+            }
+        });
+    }
 
     // Keep main thread running
-    log_info!("PTY server started, listening on port: {}", port);
+    log_info!("PTY server started, listening on {}", endpoint.address);
     
-    // Wait for Ctrl+C signal
-    tokio::signal::ctrl_c().await?;
+    // Wait for Ctrl+C, or SIGTERM from a service manager or `kill`
+    shutdown_signal().await?;
     log_info!("Received exit signal, shutting down server...");
+    server.save_sessions();
 
     Ok(())
 }
+
+/// Wait for the first signal that should stop the server
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
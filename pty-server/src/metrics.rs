@@ -0,0 +1,348 @@
+// Metrics
+//
+// Process-wide counters and histograms about sessions and terminal I/O.
+// They're cheap atomics updated on the hot path, read through the `stats`
+// request or an optional local HTTP endpoint serving `/metrics` in the
+// Prometheus text format.
+use crate::http;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Metrics of this server process
+pub static METRICS: Metrics = Metrics::new();
+
+/// Fixed-bucket histogram, `counts[i]` holds observations in `(bounds[i-1], bounds[i]]`
+pub struct Histogram<const N: usize> {
+    bounds: [f64; N],
+    counts: [AtomicU64; N],
+    count: AtomicU64,
+    /// f64 bits of the sum of observations
+    sum: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    const fn new(bounds: [f64; N]) -> Self {
+        Self {
+            bounds,
+            counts: [const { AtomicU64::new(0) }; N],
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + value).to_bits())
+        });
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self
+            .bounds
+            .iter()
+            .zip(&self.counts)
+            .map(|(&le, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                Bucket { le, count: cumulative }
+            })
+            .collect();
+        HistogramSnapshot {
+            count: self.count.load(Ordering::Relaxed),
+            sum: f64::from_bits(self.sum.load(Ordering::Relaxed)),
+            buckets,
+        }
+    }
+}
+
+/// Traffic of a single session
+#[derive(Default)]
+pub struct SessionMetrics {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl SessionMetrics {
+    /// Client input written to the PTY
+    pub fn record_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        METRICS.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// PTY output sent to the client
+    pub fn record_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        METRICS.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Where a failed write was going
+#[derive(Debug, Clone, Copy)]
+pub enum WriteTarget {
+    /// Input written to the PTY
+    Pty,
+    /// Frame sent to the client
+    Client,
+}
+
+pub struct Metrics {
+    sessions_created: AtomicU64,
+    spawn_failures: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    frames_sent: AtomicU64,
+    pty_write_errors: AtomicU64,
+    client_write_errors: AtomicU64,
+    /// Bytes returned by a single PTY read
+    pty_read_bytes: Histogram<7>,
+    /// Time to start the shell
+    spawn_seconds: Histogram<8>,
+    /// Time from starting the shell to its first output
+    first_output_seconds: Histogram<9>,
+    /// Running sessions by id
+    sessions: Mutex<BTreeMap<u64, Arc<SessionMetrics>>>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            sessions_created: AtomicU64::new(0),
+            spawn_failures: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            frames_sent: AtomicU64::new(0),
+            pty_write_errors: AtomicU64::new(0),
+            client_write_errors: AtomicU64::new(0),
+            pty_read_bytes: Histogram::new([64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0]),
+            spawn_seconds: Histogram::new([0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0]),
+            first_output_seconds: Histogram::new([0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]),
+            sessions: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// A shell was spawned, returns the session's traffic counters
+    pub fn session_started(&self, id: u64, spawn_time: Duration) -> Arc<SessionMetrics> {
+        self.sessions_created.fetch_add(1, Ordering::Relaxed);
+        self.spawn_seconds.observe(spawn_time.as_secs_f64());
+        let session = Arc::new(SessionMetrics::default());
+        self.sessions.lock().unwrap().insert(id, Arc::clone(&session));
+        session
+    }
+
+    pub fn session_ended(&self, id: u64) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+
+    pub fn spawn_failed(&self) {
+        self.spawn_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn pty_read(&self, bytes: usize) {
+        self.pty_read_bytes.observe(bytes as f64);
+    }
+
+    pub fn first_output(&self, since_spawn: Duration) {
+        self.first_output_seconds.observe(since_spawn.as_secs_f64());
+    }
+
+    pub fn frame_sent(&self) {
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn write_error(&self, target: WriteTarget) {
+        let counter = match target {
+            WriteTarget::Pty => &self.pty_write_errors,
+            WriteTarget::Client => &self.client_write_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current values, as sent in reply to `stats`
+    pub fn snapshot(&self) -> Snapshot {
+        let sessions: Vec<SessionSnapshot> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, session)| SessionSnapshot {
+                id,
+                bytes_in: session.bytes_in.load(Ordering::Relaxed),
+                bytes_out: session.bytes_out.load(Ordering::Relaxed),
+            })
+            .collect();
+
+        Snapshot {
+            sessions_created: self.sessions_created.load(Ordering::Relaxed),
+            sessions_active: sessions.len(),
+            spawn_failures: self.spawn_failures.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            write_errors: WriteErrors {
+                pty: self.pty_write_errors.load(Ordering::Relaxed),
+                client: self.client_write_errors.load(Ordering::Relaxed),
+            },
+            pty_read_bytes: self.pty_read_bytes.snapshot(),
+            spawn_seconds: self.spawn_seconds.snapshot(),
+            first_output_seconds: self.first_output_seconds.snapshot(),
+            sessions,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    pub sessions_created: u64,
+    pub sessions_active: usize,
+    pub spawn_failures: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub frames_sent: u64,
+    pub write_errors: WriteErrors,
+    pub pty_read_bytes: HistogramSnapshot,
+    pub spawn_seconds: HistogramSnapshot,
+    pub first_output_seconds: HistogramSnapshot,
+    pub sessions: Vec<SessionSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WriteErrors {
+    pub pty: u64,
+    pub client: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSnapshot {
+    pub id: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum: f64,
+    /// Cumulative counts, observations above the last bound are only in `count`
+    pub buckets: Vec<Bucket>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Bucket {
+    pub le: f64,
+    pub count: u64,
+}
+
+impl Snapshot {
+    /// Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("pty_sessions_created_total", "Sessions started", self.sessions_created),
+            ("pty_spawn_failures_total", "Shells that failed to start", self.spawn_failures),
+            ("pty_bytes_in_total", "Client input bytes written to PTYs", self.bytes_in),
+            ("pty_bytes_out_total", "PTY output bytes sent to clients", self.bytes_out),
+            ("pty_frames_sent_total", "WebSocket frames sent to clients", self.frames_sent),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+        }
+
+        let _ = writeln!(out, "# HELP pty_sessions_active Sessions running\n# TYPE pty_sessions_active gauge");
+        let _ = writeln!(out, "pty_sessions_active {}", self.sessions_active);
+
+        let _ = writeln!(out, "# HELP pty_write_errors_total Failed writes\n# TYPE pty_write_errors_total counter");
+        let _ = writeln!(out, "pty_write_errors_total{{target=\"pty\"}} {}", self.write_errors.pty);
+        let _ = writeln!(out, "pty_write_errors_total{{target=\"client\"}} {}", self.write_errors.client);
+
+        let histograms = [
+            ("pty_read_bytes", "Bytes returned by a single PTY read", &self.pty_read_bytes),
+            ("pty_spawn_seconds", "Time to start the shell", &self.spawn_seconds),
+            ("pty_first_output_seconds", "Time from starting the shell to its first output", &self.first_output_seconds),
+        ];
+        for (name, help, histogram) in histograms {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+            for bucket in &histogram.buckets {
+                let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bucket.le, bucket.count);
+            }
+            let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count);
+            let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, histogram.sum, name, histogram.count);
+        }
+
+        let per_session = [
+            ("pty_session_bytes_in_total", "Client input bytes of a running session"),
+            ("pty_session_bytes_out_total", "PTY output bytes of a running session"),
+        ];
+        for (i, (name, help)) in per_session.into_iter().enumerate() {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+            for session in &self.sessions {
+                let value = if i == 0 { session.bytes_in } else { session.bytes_out };
+                let _ = writeln!(out, "{}{{session=\"{}\"}} {}", name, session.id, value);
+            }
+        }
+        out
+    }
+}
+
+/// Serve `/metrics` over HTTP, returns the bound address
+pub async fn serve(addr: SocketAddr) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                if let Err(e) = handle_request(stream).await {
+                    log_debug!("Metrics request failed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(local_addr)
+}
+
+async fn handle_request(mut stream: TcpStream) -> std::io::Result<()> {
+    let (head, _) = http::read_head(&mut stream).await?;
+    let (status, body) = match (head.method.as_str(), head.path.as_str()) {
+        ("GET", "/metrics") => ("200 OK", METRICS.snapshot().to_prometheus()),
+        (_, "/metrics") => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+    http::respond(&mut stream, status, "text/plain; version=0.0.4; charset=utf-8", &body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new([1.0, 10.0]);
+        for value in [0.5, 1.0, 5.0, 50.0] {
+            histogram.observe(value);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 4);
+        assert_eq!(snapshot.sum, 56.5);
+        let counts: Vec<u64> = snapshot.buckets.iter().map(|b| b.count).collect();
+        assert_eq!(counts, [2, 3]);
+    }
+
+    #[test]
+    fn test_prometheus_format() {
+        let metrics = Metrics::new();
+        metrics.pty_read(100);
+        let text = metrics.snapshot().to_prometheus();
+        assert!(text.contains("# TYPE pty_sessions_created_total counter\npty_sessions_created_total 0\n"));
+        assert!(text.contains("pty_read_bytes_bucket{le=\"64\"} 0\npty_read_bytes_bucket{le=\"256\"} 1\n"));
+        assert!(text.contains("pty_read_bytes_bucket{le=\"+Inf\"} 1\npty_read_bytes_sum 100\npty_read_bytes_count 1\n"));
+    }
+}
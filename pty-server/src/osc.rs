@@ -0,0 +1,299 @@
+// OSC Sequence Parsing
+//
+// Shells report state (working directory, titles, ...) through OSC
+// (Operating System Command) escape sequences embedded in their output.
+// The parser below is a small state machine fed with raw PTY chunks, so a
+// sequence split across two reads is still recognized. It also reports
+// plain BEL characters, skipping those terminating OSC/DCS strings.
+
+/// Maximum OSC payload length, longer sequences are dropped
+const MAX_OSC_LEN: usize = 4096;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+
+/// Event extracted from an OSC sequence or control character
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OscEvent {
+    /// Working directory (OSC 7, OSC 9;9 or Git Bash OSC 0 title)
+    Cwd(String),
+    /// Window title (OSC 0 / OSC 2)
+    Title(String),
+    /// Desktop notification (OSC 9 / OSC 777;notify)
+    Notify { title: Option<String>, body: String },
+    /// BEL outside of any escape sequence, reported once per chunk
+    Bell,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Plain output
+    Ground,
+    /// After ESC, waiting for the sequence introducer
+    Escape,
+    /// Inside OSC payload
+    Osc,
+    /// After ESC inside OSC payload, expecting `\` (ST)
+    OscEscape,
+    /// Inside DCS/SOS/PM/APC string, content ignored
+    Str,
+    /// After ESC inside an ignored string
+    StrEscape,
+}
+
+/// Streaming OSC parser, keeps partial sequences across read boundaries
+pub struct OscParser {
+    state: State,
+    payload: Vec<u8>,
+    overflow: bool,
+}
+
+impl OscParser {
+    pub fn new() -> Self {
+        Self {
+            state: State::Ground,
+            payload: Vec::new(),
+            overflow: false,
+        }
+    }
+
+    /// Feed a chunk of PTY output, returns events completed in this chunk
+    pub fn feed(&mut self, data: &[u8]) -> Vec<OscEvent> {
+        let mut events = Vec::new();
+        let mut bell = false;
+
+        for &byte in data {
+            match self.state {
+                State::Ground => match byte {
+                    ESC => self.state = State::Escape,
+                    BEL => bell = true,
+                    _ => {}
+                },
+                State::Escape => match byte {
+                    b']' => {
+                        self.payload.clear();
+                        self.overflow = false;
+                        self.state = State::Osc;
+                    }
+                    b'P' | b'X' | b'^' | b'_' => self.state = State::Str,
+                    ESC => {}
+                    _ => self.state = State::Ground,
+                },
+                State::Osc => match byte {
+                    BEL => {
+                        self.dispatch(&mut events);
+                        self.state = State::Ground;
+                    }
+                    ESC => self.state = State::OscEscape,
+                    CAN | SUB => self.state = State::Ground,
+                    _ => self.push(byte),
+                },
+                State::OscEscape => {
+                    if byte == b'\\' {
+                        self.dispatch(&mut events);
+                        self.state = State::Ground;
+                    } else if byte == b']' {
+                        // Unterminated sequence followed by a new OSC
+                        self.payload.clear();
+                        self.overflow = false;
+                        self.state = State::Osc;
+                    } else {
+                        self.state = if byte == ESC { State::Escape } else { State::Ground };
+                    }
+                }
+                State::Str => match byte {
+                    ESC => self.state = State::StrEscape,
+                    CAN | SUB => self.state = State::Ground,
+                    _ => {}
+                },
+                State::StrEscape => {
+                    self.state = match byte {
+                        b'\\' => State::Ground,
+                        ESC => State::StrEscape,
+                        _ => State::Str,
+                    };
+                }
+            }
+        }
+
+        if bell {
+            events.push(OscEvent::Bell);
+        }
+        events
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.payload.len() < MAX_OSC_LEN {
+            self.payload.push(byte);
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    fn dispatch(&mut self, events: &mut Vec<OscEvent>) {
+        if self.overflow {
+            return;
+        }
+
+        let payload = String::from_utf8_lossy(&self.payload);
+        let (code, rest) = payload.split_once(';').unwrap_or((&payload, ""));
+
+        match code {
+            "0" | "2" => {
+                if let Some(cwd) = parse_gitbash_title(rest) {
+                    events.push(OscEvent::Cwd(cwd));
+                }
+                events.push(OscEvent::Title(rest.to_string()));
+            }
+            "7" => events.extend(parse_file_url(rest).map(OscEvent::Cwd)),
+            "9" => {
+                if let Some(path) = rest.strip_prefix("9;") {
+                    // ConEmu / Windows Terminal cwd
+                    let path = path.trim_matches('"');
+                    if !path.is_empty() {
+                        events.push(OscEvent::Cwd(path.to_string()));
+                    }
+                } else if !is_conemu_command(rest) && !rest.is_empty() {
+                    // iTerm2 style notification
+                    events.push(OscEvent::Notify { title: None, body: rest.to_string() });
+                }
+            }
+            "777" => {
+                // rxvt-unicode style: 777;notify;title;body
+                if let Some(params) = rest.strip_prefix("notify;") {
+                    let (title, body) = params.split_once(';').unwrap_or(("", params));
+                    let title = Some(title.to_string()).filter(|t| !t.is_empty());
+                    events.push(OscEvent::Notify { title, body: body.to_string() });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Default for OscParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// ConEmu OSC 9 commands start with a numeric id (9;4;... progress etc.)
+fn is_conemu_command(payload: &str) -> bool {
+    let id = payload.split(';').next().unwrap_or("");
+    !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()) && payload.len() > id.len()
+}
+
+/// Parse OSC 7 payload `file://hostname/path` into a local path
+fn parse_file_url(url: &str) -> Option<String> {
+    let path = match url.strip_prefix("file://") {
+        // Skip hostname, path starts at the first slash
+        Some(rest) => &rest[rest.find('/')?..],
+        None if url.starts_with('/') => url,
+        None => return None,
+    };
+
+    let path = percent_decode(path);
+
+    // Windows drive paths are reported as /C:/Users/...
+    let bytes = path.as_bytes();
+    if bytes.len() >= 3 && bytes[0] == b'/' && bytes[1].is_ascii_alphabetic() && bytes[2] == b':' {
+        return Some(path[1..].to_string());
+    }
+
+    Some(path)
+}
+
+/// Parse Git Bash window title `MINGW64:/c/Users/...` into a Windows path
+fn parse_gitbash_title(title: &str) -> Option<String> {
+    let (prefix, path) = title.split_once(':')?;
+    if !matches!(prefix, "MINGW64" | "MINGW32" | "MSYS") || path.is_empty() {
+        return None;
+    }
+
+    let bytes = path.as_bytes();
+    if bytes.len() >= 3 && bytes[0] == b'/' && bytes[1].is_ascii_alphabetic() && bytes[2] == b'/' {
+        let drive = (bytes[1] as char).to_ascii_uppercase();
+        return Some(format!("{}:{}", drive, path[2..].replace('/', "\\")));
+    }
+
+    Some(path.to_string())
+}
+
+/// Decode %XX escapes, invalid escapes are kept as-is
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(value) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                decoded.push(value);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_osc7_split_across_chunks() {
+        let mut parser = OscParser::new();
+        assert!(parser.feed(b"prompt\x1b]7;file://host/home/us").is_empty());
+        let events = parser.feed(b"er/my%20notes\x1b\\$ ");
+        assert_eq!(events, vec![OscEvent::Cwd("/home/user/my notes".to_string())]);
+    }
+
+    #[test]
+    fn test_osc9_9_and_gitbash_title() {
+        let mut parser = OscParser::new();
+        let events = parser.feed(b"\x1b]9;9;\"C:\\Users\\me\"\x07\x1b]0;MINGW64:/d/vault\x07");
+        assert_eq!(
+            events,
+            vec![
+                OscEvent::Cwd("C:\\Users\\me".to_string()),
+                OscEvent::Cwd("D:\\vault".to_string()),
+                OscEvent::Title("MINGW64:/d/vault".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_utf8_path_and_windows_drive() {
+        let mut parser = OscParser::new();
+        let events = parser.feed("\x1b]7;file:///C:/笔记\x07".as_bytes());
+        assert_eq!(events, vec![OscEvent::Cwd("C:/笔记".to_string())]);
+    }
+
+    #[test]
+    fn test_title_bell_and_notifications() {
+        let mut parser = OscParser::new();
+        let events = parser.feed(b"\x1b]2;vim notes.md\x1b\\done\x07\x07\x1b]9;Build finished\x07\x1b]777;notify;npm;exit 0\x07\x1b]9;4;1;50\x07");
+        assert_eq!(
+            events,
+            vec![
+                OscEvent::Title("vim notes.md".to_string()),
+                OscEvent::Notify { title: None, body: "Build finished".to_string() },
+                OscEvent::Notify { title: Some("npm".to_string()), body: "exit 0".to_string() },
+                OscEvent::Bell,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bell_inside_dcs_is_ignored() {
+        let mut parser = OscParser::new();
+        assert!(parser.feed(b"\x1bPq\x07data\x1b\\").is_empty());
+    }
+}
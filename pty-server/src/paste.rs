@@ -0,0 +1,152 @@
+// Paste Handling
+//
+// A `paste` message is written the way a terminal pastes: line breaks become
+// CR, control characters are stripped by the `[paste]` policy, and the text
+// is wrapped in bracketed-paste markers while the program has enabled mode
+// 2004, so shells and editors insert it instead of running each line. The
+// mode is followed in the program's output. Big pastes are written in paced
+// chunks off the runtime thread, so neither the server nor the program's
+// input buffer is overrun.
+use serde::{Deserialize, Serialize};
+
+/// Start of a bracketed paste
+const PASTE_START: &str = "\x1b[200~";
+/// End of a bracketed paste
+const PASTE_END: &str = "\x1b[201~";
+/// Longest mode sequence held back when cut off at the end of a read
+const MAX_SEQUENCE: usize = 32;
+
+/// What a paste does with control characters other than tab and line breaks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlChars {
+    /// Drop them, so a paste can't carry escape sequences or signals
+    #[default]
+    Strip,
+    /// Write them as pasted, only a bracketed paste's end marker is removed
+    Keep,
+}
+
+/// Paste text as written to the PTY
+pub fn prepare(text: &str, control: ControlChars, bracketed: bool) -> String {
+    let mut body = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' => {
+                if chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+                body.push('\r');
+            }
+            '\n' => body.push('\r'),
+            '\t' => body.push(c),
+            c if c.is_control() && control == ControlChars::Strip => {}
+            c => body.push(c),
+        }
+    }
+    if !bracketed {
+        return body;
+    }
+
+    // An end marker in the text would run the rest as typed
+    while body.contains(PASTE_END) {
+        body = body.replace(PASTE_END, "");
+    }
+    format!("{}{}{}", PASTE_START, body, PASTE_END)
+}
+
+/// Follows bracketed-paste mode (DECSET/DECRST 2004) in program output
+#[derive(Debug, Default)]
+pub struct BracketedPasteMode {
+    enabled: bool,
+    /// Start of a mode sequence cut off at the end of the last read
+    pending: Vec<u8>,
+}
+
+enum Sequence {
+    /// `ESC [ ? params h|l`, with its length
+    Mode { params: Vec<u8>, set: bool, len: usize },
+    Incomplete,
+    Other,
+}
+
+impl BracketedPasteMode {
+    /// Scan output, returns whether the mode is enabled after it
+    pub fn feed(&mut self, data: &[u8]) -> bool {
+        let joined;
+        let bytes = if self.pending.is_empty() {
+            data
+        } else {
+            self.pending.extend_from_slice(data);
+            joined = std::mem::take(&mut self.pending);
+            &joined[..]
+        };
+
+        let mut i = 0;
+        while let Some(offset) = bytes[i..].iter().position(|&b| b == 0x1b) {
+            let start = i + offset;
+            match parse(&bytes[start..]) {
+                Sequence::Mode { params, set, len } => {
+                    if params.split(|&b| b == b';').any(|param| param == b"2004") {
+                        self.enabled = set;
+                    }
+                    i = start + len;
+                }
+                Sequence::Incomplete => {
+                    if bytes.len() - start <= MAX_SEQUENCE {
+                        self.pending = bytes[start..].to_vec();
+                    }
+                    break;
+                }
+                Sequence::Other => i = start + 1,
+            }
+        }
+        self.enabled
+    }
+}
+
+/// Parse a private mode sequence at the start of `bytes` (which starts with ESC)
+fn parse(bytes: &[u8]) -> Sequence {
+    for (i, expected) in [b'[', b'?'].into_iter().enumerate() {
+        match bytes.get(i + 1) {
+            Some(&b) if b == expected => {}
+            Some(_) => return Sequence::Other,
+            None => return Sequence::Incomplete,
+        }
+    }
+    for (i, &b) in bytes.iter().enumerate().skip(3) {
+        match b {
+            b'0'..=b'9' | b';' => {}
+            b'h' | b'l' => return Sequence::Mode { params: bytes[3..i].to_vec(), set: b == b'h', len: i + 1 },
+            _ => return Sequence::Other,
+        }
+    }
+    Sequence::Incomplete
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepare() {
+        let text = "echo a\r\necho b\n\x1b[201~rm -rf ~\x03\t!";
+        assert_eq!(prepare(text, ControlChars::Strip, false), "echo a\recho b\r[201~rm -rf ~\t!");
+        assert_eq!(
+            prepare(text, ControlChars::Keep, true),
+            "\x1b[200~echo a\recho b\rrm -rf ~\x03\t!\x1b[201~"
+        );
+        // Removing one marker must not leave another
+        assert_eq!(prepare("\x1b[20\x1b[201~1~", ControlChars::Keep, true), "\x1b[200~\x1b[201~");
+    }
+
+    #[test]
+    fn test_mode_split_across_reads() {
+        let mut mode = BracketedPasteMode::default();
+        assert!(mode.feed(b"prompt \x1b[?1;2004h$ "));
+        assert!(mode.feed(b"ls\r\n\x1b[?20"));
+        assert!(!mode.feed(b"04l\x1b[?25h"));
+        assert!(!mode.feed(b"\x1b[1m\x1b[?1049h"));
+    }
+}
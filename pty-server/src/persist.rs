@@ -0,0 +1,327 @@
+// Session Persistence
+//
+// With `[persistence] enabled = true` the server saves what each session was
+// started with, its last cwd and title and the tail of its output to a JSON
+// file: periodically while it runs, and once more when it ends. Saved
+// sessions of earlier runs and sessions that ended are offered as
+// restorable; `init` with `restore` respawns the shell in the saved cwd and
+// replays the saved output, so a workspace comes back nearly as it was. The
+// file can hold anything printed to a terminal and is only readable by the
+// user.
+use crate::config::PersistenceSettings;
+use crate::sessions::SessionRegistry;
+use crate::shared::SharedSession;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Version of the saved sessions file, bumped on incompatible changes
+const FILE_VERSION: u32 = 1;
+
+/// What a session was started with, enough to start it again
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Launch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell_args: Option<Vec<String>>,
+    /// Environment overrides from init and the profile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_root: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// Encoding of the PTY, if not UTF-8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+/// Session as saved to disk
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SavedSession {
+    /// Identifies the session across restarts
+    pub key: String,
+    #[serde(flatten)]
+    pub launch: Launch,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Unix time of the last output
+    pub updated_at: u64,
+    /// Tail of the output, replayed on restore
+    #[serde(default)]
+    pub scrollback: String,
+}
+
+/// Restorable session as listed to clients, without its scrollback
+#[derive(Debug, Serialize)]
+pub struct RestorableSession {
+    pub key: String,
+    pub profile: Option<String>,
+    pub shell_type: Option<String>,
+    pub cwd: Option<String>,
+    pub title: Option<String>,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct SavedFile {
+    version: u32,
+    sessions: Vec<SavedSession>,
+}
+
+/// Saved sessions file and the sessions restorable from it
+pub struct SessionStore {
+    path: PathBuf,
+    /// Prefix of this run's session keys
+    run: String,
+    max_restorable: usize,
+    /// Sessions of earlier runs and ended sessions, newest first
+    restorable: Mutex<Vec<SavedSession>>,
+    /// Last written contents, unchanged sessions aren't written again
+    last_written: Mutex<String>,
+    /// Wakes the saver before the next interval
+    changed: tokio::sync::Notify,
+}
+
+impl SessionStore {
+    /// Load the saved sessions, a missing or unreadable file leaves nothing to restore
+    pub fn load(settings: &PersistenceSettings) -> Option<Self> {
+        let path = settings.file.clone().filter(|_| settings.enabled)?;
+        let restorable = match std::fs::read_to_string(&path) {
+            Ok(text) => match serde_json::from_str::<SavedFile>(&text) {
+                Ok(file) if file.version == FILE_VERSION => file.sessions,
+                Ok(file) => {
+                    log_warn!("Ignoring saved sessions of file version {}: {}", file.version, path.display());
+                    Vec::new()
+                }
+                Err(e) => {
+                    log_warn!("Ignoring unreadable saved sessions {}: {}", path.display(), e);
+                    Vec::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                log_warn!("Cannot read saved sessions {}: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+        log_info!("{} restorable sessions in {}", restorable.len(), path.display());
+
+        Some(Self {
+            path,
+            run: format!("{:x}", unix_millis()),
+            max_restorable: settings.max_restorable,
+            restorable: Mutex::new(restorable),
+            last_written: Mutex::new(String::new()),
+            changed: tokio::sync::Notify::new(),
+        })
+    }
+
+    /// Key of a session of this run
+    pub fn key(&self, id: u64) -> String {
+        format!("{}-{}", self.run, id)
+    }
+
+    /// Restorable sessions of a namespace
+    pub fn restorable(&self, namespace: Option<&str>) -> Vec<RestorableSession> {
+        self.restorable
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|saved| saved.launch.namespace.as_deref() == namespace)
+            .map(|saved| RestorableSession {
+                key: saved.key.clone(),
+                profile: saved.launch.profile.clone(),
+                shell_type: saved.launch.shell_type.clone(),
+                cwd: saved.cwd.clone(),
+                title: saved.title.clone(),
+                updated_at: saved.updated_at,
+            })
+            .collect()
+    }
+
+    /// Restorable session of a namespace
+    pub fn get(&self, key: &str, namespace: Option<&str>) -> Option<SavedSession> {
+        let restorable = self.restorable.lock().unwrap();
+        restorable.iter().find(|saved| saved.key == key && saved.launch.namespace.as_deref() == namespace).cloned()
+    }
+
+    /// Drop a restorable session, once restored or discarded by a client
+    pub fn remove(&self, key: &str) -> bool {
+        let mut restorable = self.restorable.lock().unwrap();
+        let len = restorable.len();
+        restorable.retain(|saved| saved.key != key);
+        let removed = restorable.len() != len;
+        if removed {
+            self.changed.notify_one();
+        }
+        removed
+    }
+
+    /// Keep an ended session for restoring, the oldest are dropped beyond the limit
+    pub fn session_ended(&self, shared: &SharedSession, sessions: &SessionRegistry) {
+        let Some(saved) = self.snapshot(shared, sessions) else {
+            return;
+        };
+        let mut restorable = self.restorable.lock().unwrap();
+        restorable.insert(0, saved);
+        restorable.truncate(self.max_restorable);
+        self.changed.notify_one();
+    }
+
+    fn snapshot(&self, shared: &SharedSession, sessions: &SessionRegistry) -> Option<SavedSession> {
+        let info = sessions.get(shared.id)?;
+        let (scrollback, updated_at) = shared.scrollback();
+        // The encoding may have been switched since the start
+        let encoding = shared.encoding().map(|encoding| encoding.name().to_string());
+        Some(SavedSession {
+            key: self.key(shared.id),
+            launch: Launch { encoding, ..shared.settings.launch.clone() },
+            cwd: info.cwd,
+            title: info.title,
+            updated_at,
+            scrollback: String::from_utf8_lossy(&scrollback).into_owned(),
+        })
+    }
+
+    /// Contents of the file for the running and restorable sessions
+    fn contents(&self, sessions: &SessionRegistry) -> String {
+        let restorable = self.restorable.lock().unwrap().clone();
+        // A session being torn down is already restorable
+        let mut saved: Vec<SavedSession> = sessions
+            .all_shared()
+            .iter()
+            .filter_map(|shared| self.snapshot(shared, sessions))
+            .filter(|saved| !restorable.iter().any(|ended| ended.key == saved.key))
+            .collect();
+        saved.extend(restorable);
+        let file = SavedFile { version: FILE_VERSION, sessions: saved };
+        serde_json::to_string(&file).unwrap_or_default()
+    }
+
+    /// Save now if anything changed since the last save
+    pub fn save(&self, sessions: &SessionRegistry) -> io::Result<()> {
+        let contents = self.contents(sessions);
+        let mut last_written = self.last_written.lock().unwrap();
+        if *last_written == contents {
+            return Ok(());
+        }
+        write_private(&self.path, contents.as_bytes())?;
+        *last_written = contents;
+        Ok(())
+    }
+
+    /// Save every `interval`, or sooner when a session ends or is restored
+    pub async fn run(self: Arc<Self>, sessions: SessionRegistry, interval: Duration) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = self.changed.notified() => {}
+            }
+            let store = Arc::clone(&self);
+            let sessions = sessions.clone();
+            let result = tokio::task::spawn_blocking(move || store.save(&sessions).map_err(|e| e.to_string())).await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log_warn!("Cannot save sessions to {}: {}", self.path.display(), e),
+                Err(e) => log_error!("Session save task failed: {}", e),
+            }
+        }
+    }
+}
+
+/// Tail of a session's output
+pub struct Scrollback {
+    buf: Vec<u8>,
+    limit: usize,
+    /// Older output was dropped, the buffer may start mid-line
+    cut: bool,
+    /// Unix time of the last output
+    updated_at: u64,
+}
+
+impl Scrollback {
+    /// Keeps up to `limit` bytes, nothing with 0
+    pub fn new(limit: usize) -> Self {
+        Self { buf: Vec::new(), limit, cut: false, updated_at: unix_millis() / 1000 }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        if self.limit == 0 {
+            return;
+        }
+        self.buf.extend_from_slice(data);
+        self.updated_at = unix_millis() / 1000;
+        // Trim in large steps so pushes stay cheap
+        if self.buf.len() > self.limit * 2 {
+            let excess = self.buf.len() - self.limit;
+            self.buf.drain(..excess);
+            self.cut = true;
+        }
+    }
+
+    /// Up to `limit` bytes, starting at a line if older output was dropped
+    pub fn tail(&self) -> &[u8] {
+        if self.buf.len() <= self.limit && !self.cut {
+            return &self.buf;
+        }
+        let tail = &self.buf[self.buf.len().saturating_sub(self.limit)..];
+        match tail.iter().position(|&b| b == b'\n') {
+            Some(newline) => &tail[newline + 1..],
+            None => tail,
+        }
+    }
+
+    pub fn updated_at(&self) -> u64 {
+        self.updated_at
+    }
+}
+
+/// Replace `path` with `contents`, readable only by the user
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    io::Write::write_all(&mut options.open(&tmp)?, contents)?;
+    std::fs::rename(&tmp, path)
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrollback_tail() {
+        let mut scrollback = Scrollback::new(8);
+        scrollback.push(b"ab\ncd");
+        assert_eq!(scrollback.tail(), b"ab\ncd");
+
+        scrollback.push(b"ef\ngh\nijklmn");
+        // Cut to the last 8 bytes, then to the next line
+        assert_eq!(scrollback.tail(), b"ijklmn");
+
+        let mut disabled = Scrollback::new(0);
+        disabled.push(b"output");
+        assert!(disabled.tail().is_empty());
+    }
+}
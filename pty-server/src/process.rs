@@ -0,0 +1,165 @@
+// Process Inspection
+use serde::Serialize;
+
+/// Name and command line of a process
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub name: String,
+    pub argv: Vec<String>,
+}
+
+/// Resolve process name and argv from /proc
+#[cfg(target_os = "linux")]
+pub fn process_info(pid: u32) -> Option<ProcessInfo> {
+    let name = std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let argv = cmdline
+        .split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+
+    Some(ProcessInfo {
+        pid,
+        name: name.trim_end().to_string(),
+        argv,
+    })
+}
+
+/// Resolve process name and argv via ps (no /proc on this platform)
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn process_info(pid: u32) -> Option<ProcessInfo> {
+    let ps = |field: &str| -> Option<String> {
+        let output = std::process::Command::new("ps")
+            .args(["-o", field, "-p", &pid.to_string()])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+
+    let comm = ps("comm=")?;
+    let name = comm.rsplit('/').next().unwrap_or(&comm).to_string();
+    // ps doesn't preserve argument boundaries, split on whitespace
+    let argv = ps("args=")
+        .map(|args| args.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default();
+
+    Some(ProcessInfo { pid, name, argv })
+}
+
+/// Windows ConPTY doesn't expose a foreground process group
+#[cfg(windows)]
+pub fn process_info(_pid: u32) -> Option<ProcessInfo> {
+    None
+}
+
+/// Process with its child processes
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessNode {
+    #[serde(flatten)]
+    pub process: ProcessInfo,
+    pub children: Vec<ProcessNode>,
+}
+
+/// List (pid, ppid) of all processes from /proc
+#[cfg(target_os = "linux")]
+fn process_table() -> Vec<(u32, u32)> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .filter_map(|pid| {
+            let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+            // Format: pid (comm) state ppid ..., comm may contain spaces and parens
+            let rest = &stat[stat.rfind(')')? + 1..];
+            let ppid = rest.split_whitespace().nth(1)?.parse().ok()?;
+            Some((pid, ppid))
+        })
+        .collect()
+}
+
+/// List (pid, ppid) of all processes via ps
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_table() -> Vec<(u32, u32)> {
+    let Ok(output) = std::process::Command::new("ps").args(["-A", "-o", "pid=,ppid="]).output() else {
+        return Vec::new();
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pid = fields.next()?.parse().ok()?;
+            let ppid = fields.next()?.parse().ok()?;
+            Some((pid, ppid))
+        })
+        .collect()
+}
+
+#[cfg(windows)]
+fn process_table() -> Vec<(u32, u32)> {
+    Vec::new()
+}
+
+/// List all descendants of a process (children first, depth-first)
+pub fn descendant_pids(pid: u32) -> Vec<u32> {
+    fn walk(table: &[(u32, u32)], parent: u32, out: &mut Vec<u32>) {
+        for &(pid, ppid) in table {
+            if ppid == parent && pid != parent {
+                out.push(pid);
+                walk(table, pid, out);
+            }
+        }
+    }
+
+    let table = process_table();
+    let mut pids = Vec::new();
+    walk(&table, pid, &mut pids);
+    pids
+}
+
+/// Check whether a process still exists and is not a zombie
+#[cfg(unix)]
+pub fn is_running(pid: u32) -> bool {
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } != 0 {
+        // ESRCH: gone, EPERM: exists but owned by someone else
+        return std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+        let state = stat.rfind(')').and_then(|i| stat[i + 1..].split_whitespace().next());
+        state != Some("Z")
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        true
+    }
+}
+
+/// Build the tree of child processes of a process
+pub fn child_process_tree(pid: u32) -> Vec<ProcessNode> {
+    let table = process_table();
+    build_children(&table, pid)
+}
+
+fn build_children(table: &[(u32, u32)], parent: u32) -> Vec<ProcessNode> {
+    table
+        .iter()
+        .filter(|&&(pid, ppid)| ppid == parent && pid != parent)
+        .filter_map(|&(pid, _)| {
+            Some(ProcessNode {
+                process: process_info(pid)?,
+                children: build_children(table, pid),
+            })
+        })
+        .collect()
+}
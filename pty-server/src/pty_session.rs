@@ -1,12 +1,111 @@
 // PTY Session Management
-use portable_pty::{native_pty_system, Child, MasterPty, PtySize};
+use crate::environment::EnvPolicy;
+use crate::integration::ShellIntegration;
+use crate::shell::LaunchMode;
+use portable_pty::{native_pty_system, Child, CommandBuilder, MasterPty, PtySize};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Signal sent during session teardown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TeardownSignal {
+    #[serde(rename = "SIGHUP")]
+    Hup,
+    #[serde(rename = "SIGTERM")]
+    Term,
+    #[serde(rename = "SIGKILL")]
+    Kill,
+}
+
+#[cfg(unix)]
+impl TeardownSignal {
+    fn as_raw(self) -> libc::c_int {
+        match self {
+            TeardownSignal::Hup => libc::SIGHUP,
+            TeardownSignal::Term => libc::SIGTERM,
+            TeardownSignal::Kill => libc::SIGKILL,
+        }
+    }
+}
+
+/// How a session is torn down when its client goes away
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TeardownPolicy {
+    /// Signals sent in order, escalating while processes remain
+    pub signals: Vec<TeardownSignal>,
+    /// Time to wait for processes to exit after each signal
+    pub grace_ms: u64,
+    /// Also walk and signal every descendant of the shell, not only its process groups
+    pub descendants: bool,
+}
+
+impl Default for TeardownPolicy {
+    fn default() -> Self {
+        Self {
+            signals: vec![TeardownSignal::Hup, TeardownSignal::Term, TeardownSignal::Kill],
+            grace_ms: 500,
+            descendants: true,
+        }
+    }
+}
+
+/// Outcome of a session teardown
+#[derive(Debug, Default)]
+pub struct TeardownReport {
+    /// Signals that were actually sent
+    pub signals_sent: Vec<TeardownSignal>,
+    /// Processes still alive after the last signal
+    pub survivors: Vec<u32>,
+}
+
+/// What to spawn in a new session, filled from the init command
+#[derive(Debug, Clone)]
+pub struct SpawnOptions {
+    /// Shell type (cmd, powershell, wsl, bash, zsh, custom:/path)
+    pub shell_type: Option<String>,
+    /// Shell startup arguments
+    pub shell_args: Option<Vec<String>>,
+    /// Login/interactive startup, translated into the shell's flags
+    pub mode: LaunchMode,
+    /// Working directory
+    pub cwd: Option<String>,
+    /// Environment variables set on top of the inherited environment
+    pub env: Option<HashMap<String, String>>,
+    /// Which inherited environment variables reach the shell
+    pub env_policy: EnvPolicy,
+    /// Captured login environment replacing the server's, if any
+    pub base_env: Option<Arc<Vec<(String, String)>>>,
+    /// Load shell integration (cwd tracking)
+    pub integration: bool,
+}
+
+impl Default for SpawnOptions {
+    fn default() -> Self {
+        Self {
+            shell_type: None,
+            shell_args: None,
+            mode: LaunchMode::default(),
+            cwd: None,
+            env: None,
+            env_policy: EnvPolicy::default(),
+            base_env: None,
+            integration: true,
+        }
+    }
+}
 
 /// PTY Session
 pub struct PtySession {
     master: Box<dyn MasterPty + Send>,
     child: Arc<Mutex<Box<dyn Child + Send + Sync>>>,
+    /// Integration files live as long as the session
+    _integration: Option<ShellIntegration>,
+    /// Environment the shell was started with
+    environment: BTreeMap<String, String>,
 }
 
 /// PTY Reader (independent, no lock needed)
@@ -21,18 +120,7 @@ pub struct PtyWriter {
 
 impl PtySession {
     /// Create a new PTY session, returns (session, reader, writer)
-    /// shell_type: Optional shell type (cmd, powershell, wsl, bash, zsh, custom:/path)
-    /// shell_args: Optional shell startup arguments
-    /// cwd: Optional working directory
-    /// env: Optional environment variables
-    pub fn new(
-        cols: u16, 
-        rows: u16, 
-        shell_type: Option<&str>,
-        shell_args: Option<&[String]>,
-        cwd: Option<&str>,
-        env: Option<&std::collections::HashMap<String, String>>
-    ) -> Result<(Self, PtyReader, PtyWriter), Box<dyn std::error::Error>> {
+    pub fn new(cols: u16, rows: u16, options: &SpawnOptions) -> Result<(Self, PtyReader, PtyWriter), Box<dyn std::error::Error>> {
         // Get PTY system
         let pty_system = native_pty_system();
         
@@ -45,35 +133,49 @@ impl PtySession {
         })?;
         
         // Get command based on shell type
-        let mut cmd = crate::shell::get_shell_by_type(shell_type);
+        let mut cmd = crate::shell::get_shell_by_type(options.shell_type.as_deref())?;
         
-        // Add startup arguments
-        if let Some(args) = shell_args {
-            for arg in args {
-                cmd.arg(arg);
+        // Start from the login environment when it was captured
+        if let Some(base_env) = &options.base_env {
+            cmd.env_clear();
+            for (key, value) in base_env.iter() {
+                cmd.env(key, value);
             }
         }
         
+        // Filter the inherited environment before anything is set on top
+        options.env_policy.apply(&mut cmd);
+        
+        let shell_args = options.shell_args.as_deref().unwrap_or_default();
+        let mut cmd = crate::shell::apply_launch_mode(cmd, options.mode, !shell_args.is_empty());
+        
+        // Add startup arguments
+        for arg in shell_args {
+            cmd.arg(arg);
+        }
+        
         // Set working directory
-        if let Some(cwd_path) = cwd {
+        if let Some(cwd_path) = &options.cwd {
             cmd.cwd(cwd_path);
         }
         
         // Set environment variables
         // Ensure TERM environment variable exists, otherwise clear/vim etc. won't work properly
+        let env = options.env.as_ref();
+        let inherited = |cmd: &CommandBuilder, var: &str| cmd.get_env(var).and_then(|v| v.to_str()).map(str::to_string);
         let term_value = env
             .and_then(|e| e.get("TERM").cloned())
-            .or_else(|| std::env::var("TERM").ok())
+            .or_else(|| inherited(&cmd, "TERM"))
             .unwrap_or_else(|| "xterm-256color".to_string());
         cmd.env("TERM", term_value);
         
         // Set UTF-8 locale environment variables to ensure non-ASCII characters display correctly
-        // Priority: user-provided value > system environment variable > UTF-8 default
+        // Priority: user-provided value > inherited environment variable > UTF-8 default
         let locale_vars = ["LANG", "LC_ALL", "LC_CTYPE"];
         for var in &locale_vars {
             let value = env
                 .and_then(|e| e.get(*var).cloned())
-                .or_else(|| std::env::var(*var).ok())
+                .or_else(|| inherited(&cmd, var))
                 .unwrap_or_else(|| {
                     // macOS/Linux default to en_US.UTF-8 for UTF-8 encoding
                     "en_US.UTF-8".to_string()
@@ -94,6 +196,14 @@ impl PtySession {
         // Mark this as Smart Workflow terminal
         cmd.env("TERM_PROGRAM", "smart-workflow");
         
+        // Load shell integration through the shell's startup files
+        let integration = if options.integration { crate::integration::prepare(&mut cmd)? } else { None };
+        
+        let environment = cmd
+            .iter_full_env_as_str()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        
         // Start shell process
         let child = pair.slave.spawn_command(cmd)?;
         
@@ -108,6 +218,8 @@ impl PtySession {
         let session = Self {
             master: pair.master,
             child: Arc::new(Mutex::new(child)),
+            _integration: integration,
+            environment,
         };
         
         Ok((session, reader, writer))
@@ -124,6 +236,29 @@ impl PtySession {
         Ok(())
     }
     
+    /// Environment the shell was started with
+    pub fn environment(&self) -> &BTreeMap<String, String> {
+        &self.environment
+    }
+
+    /// Get the shell process id
+    pub fn pid(&self) -> Option<u32> {
+        self.child.lock().ok().and_then(|child| child.process_id())
+    }
+
+    /// Get the foreground process group of the terminal (tcgetpgrp on the master)
+    pub fn foreground_pgid(&self) -> Option<u32> {
+        #[cfg(unix)]
+        {
+            self.master.process_group_leader().map(|pgid| pgid as u32)
+        }
+
+        #[cfg(not(unix))]
+        {
+            None
+        }
+    }
+
     /// Terminate child process
     pub fn kill(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         if let Ok(mut child) = self.child.lock() {
@@ -131,6 +266,81 @@ impl PtySession {
         }
         Ok(())
     }
+
+    /// Terminate the shell, its process groups and (optionally) all descendants,
+    /// escalating through the policy's signals until everything exited.
+    /// Blocks for up to `grace_ms` per signal.
+    #[cfg(unix)]
+    pub fn terminate(&mut self, policy: &TeardownPolicy) -> TeardownReport {
+        let mut report = TeardownReport::default();
+        let Some(shell_pid) = self.pid() else {
+            return report;
+        };
+        if policy.signals.is_empty() {
+            // No escalation configured, only signal the shell
+            let _ = self.kill();
+            return report;
+        }
+
+        // Snapshot the tree before the shell dies and its children get reparented
+        let mut pids = vec![shell_pid];
+        if policy.descendants {
+            pids.extend(crate::process::descendant_pids(shell_pid));
+        }
+        // The shell leads its own process group (setsid), jobs may run in others
+        let mut groups = vec![shell_pid];
+        if let Some(pgid) = self.foreground_pgid().filter(|&pgid| pgid != shell_pid) {
+            groups.push(pgid);
+        }
+
+        for &signal in &policy.signals {
+            let alive: Vec<u32> = pids.iter().copied().filter(|&pid| self.is_alive(pid)).collect();
+            if alive.is_empty() {
+                break;
+            }
+
+            report.signals_sent.push(signal);
+            unsafe {
+                for &pgid in &groups {
+                    libc::kill(-(pgid as libc::pid_t), signal.as_raw());
+                }
+                for &pid in &alive {
+                    libc::kill(pid as libc::pid_t, signal.as_raw());
+                }
+            }
+
+            let deadline = Instant::now() + Duration::from_millis(policy.grace_ms);
+            while Instant::now() < deadline && pids.iter().any(|&pid| self.is_alive(pid)) {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        }
+
+        report.survivors = pids.into_iter().filter(|&pid| self.is_alive(pid)).collect();
+        report
+    }
+
+    /// Windows has no process groups, fall back to killing the shell
+    #[cfg(not(unix))]
+    pub fn terminate(&mut self, _policy: &TeardownPolicy) -> TeardownReport {
+        let mut report = TeardownReport::default();
+        if self.kill().is_ok() {
+            report.signals_sent.push(TeardownSignal::Kill);
+        }
+        report
+    }
+
+    /// Check liveness, reaping the shell if it already exited
+    #[cfg(unix)]
+    fn is_alive(&self, pid: u32) -> bool {
+        if Some(pid) == self.pid() {
+            if let Ok(mut child) = self.child.lock() {
+                if let Ok(Some(_)) = child.try_wait() {
+                    return false;
+                }
+            }
+        }
+        crate::process::is_running(pid)
+    }
 }
 
 impl PtyReader {
@@ -1,32 +1,32 @@
 // WebSocket Server Implementation
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
 use futures_util::{StreamExt, SinkExt};
 use serde::{Deserialize, Serialize};
-use crate::pty_session::PtySession;
+use crate::http::{self, RequestHead};
+use crate::linkify::Linkifier;
+use crate::logging::{self, Level};
+use crate::metrics::{Snapshot, WriteTarget, METRICS};
+use crate::osc::{OscEvent, OscParser};
+use crate::paste::{self, BracketedPasteMode};
+use crate::process::{child_process_tree, process_info, ProcessInfo, ProcessNode};
+use crate::config::{Config, ProfileEntry, ProfileError, ProfileLimits, Transport};
+use crate::environment::{BaseEnv, BaseEnvCache, EnvPolicy};
+use crate::instance::Endpoint;
+use crate::persist::{Launch, RestorableSession, SavedSession, SessionStore};
+use crate::pty_session::{PtySession, SpawnOptions, TeardownPolicy};
+use crate::sessions::{is_inside_vault, SessionInfo, SessionLimit, SessionRegistry};
+use crate::shared::{Input, Role, SessionSettings, SharedSession, SizePolicy, Sharing, TakeControl};
+use crate::transcode::{self, OutputDecoder};
+use crate::utf8::{to_text, InvalidUtf8, OutputFormat, OutputMode, Utf8Boundary};
+use crate::web;
+use crate::shell::{discover_shells, ShellEntry, ShellSpecError};
 use tokio::sync::Mutex as TokioMutex;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, Mutex};
-
-/// Logging macros
-macro_rules! log_info {
-    ($($arg:tt)*) => {
-        eprintln!("[INFO] {}", format!($($arg)*));
-    };
-}
-
-macro_rules! log_error {
-    ($($arg:tt)*) => {
-        eprintln!("[ERROR] {}", format!($($arg)*));
-    };
-}
-
-macro_rules! log_debug {
-    ($($arg:tt)*) => {
-        if cfg!(debug_assertions) {
-            eprintln!("[DEBUG] {}", format!($($arg)*));
-        }
-    };
-}
+use std::time::{Duration, Instant};
 
 /// WebSocket command message
 #[derive(Debug, Deserialize, Serialize)]
@@ -44,72 +44,543 @@ pub enum Command {
     },
     
     #[serde(rename = "init")]
-    Init {
+    Init(Box<InitRequest>),
+
+    /// Clipboard text, written as a terminal pastes it
+    #[serde(rename = "paste")]
+    Paste { data: String },
+
+    /// Join a running session instead of starting one (first message only)
+    #[serde(rename = "attach")]
+    Attach {
+        session: u64,
         #[serde(skip_serializing_if = "Option::is_none")]
-        shell_type: Option<String>,
+        role: Option<Role>,
+        /// Namespace the session was started in
         #[serde(skip_serializing_if = "Option::is_none")]
-        shell_args: Option<Vec<String>>,
+        namespace: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
-        cwd: Option<String>,
+        output: Option<OutputMode>,
         #[serde(skip_serializing_if = "Option::is_none")]
-        env: Option<std::collections::HashMap<String, String>>,
+        invalid_utf8: Option<InvalidUtf8>,
+    },
+
+    /// Become the session's writer in exclusive sharing
+    #[serde(rename = "take_control")]
+    TakeControl,
+
+    /// Switch the encoding of output and input, e.g. after ssh to a GBK host
+    #[serde(rename = "set_encoding")]
+    SetEncoding { encoding: String },
+
+    /// List saved sessions that can be restored
+    #[serde(rename = "list_restorable")]
+    ListRestorable,
+
+    /// Forget a saved session
+    #[serde(rename = "discard_restorable")]
+    DiscardRestorable { key: String },
+
+    #[serde(rename = "list_sessions")]
+    ListSessions,
+
+    /// Query whether processes other than the shell are running
+    #[serde(rename = "busy")]
+    Busy,
+
+    /// List shells installed on this machine
+    #[serde(rename = "list_shells")]
+    ListShells,
+
+    /// List profiles from the config file
+    #[serde(rename = "list_profiles")]
+    ListProfiles,
+
+    /// Query the environment the shell was started with
+    #[serde(rename = "get_env")]
+    GetEnv,
+
+    /// Change the server's log level until restart
+    #[serde(rename = "set_log_level")]
+    SetLogLevel { level: Level },
+
+    /// Query server metrics
+    #[serde(rename = "stats")]
+    Stats,
+}
+
+impl Command {
+    /// The command's `type`, for logs that must not carry paste text or env values
+    fn kind(&self) -> &'static str {
+        match self {
+            Command::Resize { .. } => "resize",
+            Command::Env { .. } => "env",
+            Command::Init(_) => "init",
+            Command::Paste { .. } => "paste",
+            Command::Attach { .. } => "attach",
+            Command::TakeControl => "take_control",
+            Command::SetEncoding { .. } => "set_encoding",
+            Command::ListRestorable => "list_restorable",
+            Command::DiscardRestorable { .. } => "discard_restorable",
+            Command::ListSessions => "list_sessions",
+            Command::Busy => "busy",
+            Command::ListShells => "list_shells",
+            Command::ListProfiles => "list_profiles",
+            Command::GetEnv => "get_env",
+            Command::SetLogLevel { .. } => "set_log_level",
+            Command::Stats => "stats",
+        }
+    }
+}
+
+/// First message of a new session, every field is optional
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct InitRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shell_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shell_args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cwd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env: Option<std::collections::HashMap<String, String>>,
+    /// Vault root used to flag cwd changes inside the vault
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vault_root: Option<String>,
+    /// How to terminate the session's processes on close
+    #[serde(skip_serializing_if = "Option::is_none")]
+    teardown: Option<TeardownPolicy>,
+    /// Wrap paths to vault files in OSC 8 hyperlinks (requires vault_root)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    linkify: Option<bool>,
+    /// Start a login shell (profile files are sourced)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    login: Option<bool>,
+    /// Force interactive mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interactive: Option<bool>,
+    /// Which inherited environment variables reach the shell, overrides the server default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env_policy: Option<EnvPolicy>,
+    /// Named profile from the config file, other init fields override it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+    /// Who may write once other clients attach, overrides `[sharing] mode`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sharing: Option<Sharing>,
+    /// Which client sizes the PTY follows, overrides `[sharing] size_policy`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size_policy: Option<SizePolicy>,
+    /// Key of a saved session to start again, other init fields override it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restore: Option<String>,
+    /// Keeps the session apart from other clients' (e.g. one per vault) on a shared instance
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    /// `binary` frames (default) or `output` events in text frames
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<OutputMode>,
+    /// What text output does with invalid UTF-8: `replace` (default), `drop` or `escape`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    invalid_utf8: Option<InvalidUtf8>,
+    /// Encoding the PTY speaks (gbk, shift_jis, latin1, ...), output is transcoded to UTF-8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+}
+
+/// WebSocket event message (server -> client, sent as text frames)
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    /// Terminal output for clients that asked for text output
+    #[serde(rename = "output")]
+    Output { data: String },
+
+    #[serde(rename = "cwd_changed")]
+    CwdChanged { cwd: String, in_vault: bool },
+
+    #[serde(rename = "title_changed")]
+    TitleChanged { title: String },
+
+    #[serde(rename = "bell")]
+    Bell,
+
+    /// Desktop notification requested by a program (OSC 9 / OSC 777)
+    #[serde(rename = "notify")]
+    Notify {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+        body: String,
     },
+
+    #[serde(rename = "sessions")]
+    Sessions { sessions: Vec<SessionInfo> },
+
+    /// Sent to a client once it is attached to a session, new or running
+    #[serde(rename = "attached")]
+    Attached {
+        session: u64,
+        client: u64,
+        role: Role,
+        /// Whether the client's input reaches the shell
+        controlling: bool,
+        sharing: Sharing,
+        size_policy: SizePolicy,
+        /// Attached clients, including this one
+        clients: usize,
+        /// Encoding of the PTY, output is always sent as UTF-8
+        encoding: &'static str,
+        /// Key to restore the session with after a restart, with persistence enabled
+        #[serde(skip_serializing_if = "Option::is_none")]
+        restore_key: Option<String>,
+    },
+
+    /// Reply to `list_restorable` and `discard_restorable`, newest first
+    #[serde(rename = "restorable")]
+    Restorable { sessions: Vec<RestorableSession> },
+
+    /// A client switched the session's encoding
+    #[serde(rename = "encoding_changed")]
+    EncodingChanged { encoding: &'static str },
+
+    /// The client gained or lost control of input
+    #[serde(rename = "control_changed")]
+    ControlChanged { controlling: bool },
+
+    /// Foreground process of the terminal changed, `is_shell` when back at the prompt
+    #[serde(rename = "foreground_changed")]
+    ForegroundChanged {
+        #[serde(flatten)]
+        process: ProcessInfo,
+        is_shell: bool,
+    },
+
+    /// Reply to `busy`, `processes` is the tree of processes started from the shell
+    #[serde(rename = "busy")]
+    Busy { busy: bool, processes: Vec<ProcessNode> },
+
+    #[serde(rename = "shells")]
+    Shells { shells: Vec<ShellEntry> },
+
+    /// Profiles from the config file
+    #[serde(rename = "profiles")]
+    Profiles { profiles: Vec<ProfileEntry> },
+
+    /// Environment the shell was started with, for debugging
+    #[serde(rename = "environment")]
+    Environment {
+        /// Base the policy was applied to: "server" or "login"
+        base: &'static str,
+        /// Why the login environment couldn't be captured
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        env: BTreeMap<String, String>,
+    },
+
+    /// Reply to `stats`
+    #[serde(rename = "stats")]
+    Stats { stats: Snapshot },
+
+    /// Request or session failure, `code` is stable for clients to match on
+    #[serde(rename = "error")]
+    Error { code: String, message: String },
+}
+
+/// Byte stream a WebSocket runs over, TCP or a Unix domain socket
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+pub type WsSender = Arc<TokioMutex<futures_util::stream::SplitSink<
+    tokio_tungstenite::WebSocketStream<Box<dyn Connection>>,
+    Message,
+>>>;
+
+/// Send an event to the client as a JSON text frame
+async fn send_event(ws_sender: &WsSender, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+    let text = serde_json::to_string(event)?;
+    if let Err(e) = ws_sender.lock().await.send(Message::Text(text)).await {
+        METRICS.write_error(WriteTarget::Client);
+        return Err(e.into());
+    }
+    METRICS.frame_sent();
+    Ok(())
 }
 
 /// WebSocket server configuration
+#[derive(Clone)]
 pub struct ServerConfig {
-    pub port: u16,
+    /// Settings from the config file and command line
+    pub settings: Arc<Config>,
+    /// Process start, for the uptime in `/info`
+    pub started: Instant,
+    /// Base environment of all sessions, optionally the captured login environment
+    pub base_env: Arc<BaseEnvCache>,
+    /// Saved sessions, with persistence enabled
+    pub store: Option<Arc<SessionStore>>,
 }
 
 /// WebSocket server
 pub struct Server {
     config: ServerConfig,
+    sessions: SessionRegistry,
 }
 
 impl Server {
     pub fn new(config: ServerConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            sessions: SessionRegistry::new(),
+        }
+    }
+
+    /// Start the server, prints and returns where clients reach it
+    pub async fn start(&self) -> Result<Endpoint, Box<dyn std::error::Error>> {
+        let settings = &self.config.settings;
+        let mut endpoint = match settings.server.transport {
+            Transport::Tcp => self.start_tcp().await?,
+            Transport::Unix => self.start_unix().await?,
+        };
+        // Launchers of a shared instance pass the token on to their clients
+        if settings.instance.shared {
+            endpoint.token = settings.auth.token.clone();
+        }
+
+        // Output endpoint info to stdout (JSON format)
+        println!("{}", serde_json::to_string(&endpoint)?);
+
+        if let Some(store) = &self.config.store {
+            let interval = Duration::from_secs(settings.persistence.interval_secs);
+            tokio::spawn(Arc::clone(store).run(self.sessions.clone(), interval));
+        }
+        Ok(endpoint)
+    }
+
+    /// Save running sessions before shutting down
+    pub fn save_sessions(&self) {
+        if let Some(store) = &self.config.store {
+            if let Err(e) = store.save(&self.sessions) {
+                log_warn!("Cannot save sessions: {}", e);
+            }
+        }
     }
 
-    /// Start the server
-    pub async fn start(&self) -> Result<u16, Box<dyn std::error::Error>> {
-        let addr = format!("127.0.0.1:{}", self.config.port);
-        let listener = TcpListener::bind(&addr).await?;
+    async fn start_tcp(&self) -> Result<Endpoint, Box<dyn std::error::Error>> {
+        let server = &self.config.settings.server;
+        let listener = TcpListener::bind((server.bind, server.port)).await?;
         let local_addr = listener.local_addr()?;
-        let port = local_addr.port();
 
         log_info!("Server bound to {}", local_addr);
 
-        // Output port info to stdout (JSON format)
-        println!(
-            r#"{{"port": {}, "pid": {}}}"#,
-            port,
-            std::process::id()
-        );
-
         // Main loop: accept WebSocket connections
+        let sessions = self.sessions.clone();
+        let config = Arc::new(self.config.clone());
         tokio::spawn(async move {
             log_info!("Listening for WebSocket connections...");
             while let Ok((stream, addr)) = listener.accept().await {
                 log_debug!("Accepted connection from {}", addr);
-                tokio::spawn(async move {
-                    if let Err(e) = handle_connection(stream).await {
-                        log_error!("Connection handling error: {}", e);
-                    }
-                });
+                spawn_connection(Box::new(stream), sessions.clone(), Arc::clone(&config));
+            }
+        });
+
+        Ok(Endpoint {
+            port: Some(local_addr.port()),
+            pid: std::process::id(),
+            address: local_addr.to_string(),
+            ..Endpoint::default()
+        })
+    }
+
+    #[cfg(unix)]
+    async fn start_unix(&self) -> Result<Endpoint, Box<dyn std::error::Error>> {
+        let path = self.config.settings.server.socket.clone().ok_or("unix transport requires a socket path")?;
+
+        // A socket file left by a previous run would make bind fail, anything else stays
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            use std::os::unix::fs::FileTypeExt;
+            if !metadata.file_type().is_socket() {
+                return Err(format!("{} exists and is not a socket", path.display()).into());
+            }
+            std::fs::remove_file(&path)?;
+        }
+
+        // No token is checked on this transport, only the owner may connect.
+        // The umask applies while bind creates the file, so it's never open to others.
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = tokio::net::UnixListener::bind(&path);
+        unsafe { libc::umask(umask) };
+        let listener = listener?;
+
+        log_info!("Server bound to {}", path.display());
+
+        let sessions = self.sessions.clone();
+        let config = Arc::new(self.config.clone());
+        tokio::spawn(async move {
+            log_info!("Listening for WebSocket connections...");
+            while let Ok((stream, _)) = listener.accept().await {
+                log_debug!("Accepted connection on unix socket");
+                spawn_connection(Box::new(stream), sessions.clone(), Arc::clone(&config));
             }
         });
 
-        Ok(port)
+        Ok(Endpoint {
+            address: path.display().to_string(),
+            socket: Some(path),
+            pid: std::process::id(),
+            ..Endpoint::default()
+        })
+    }
+
+    #[cfg(not(unix))]
+    async fn start_unix(&self) -> Result<Endpoint, Box<dyn std::error::Error>> {
+        Err("unix transport is not supported on this platform".into())
+    }
+}
+
+/// Handle a connection on its own task
+fn spawn_connection(stream: Box<dyn Connection>, sessions: SessionRegistry, config: Arc<ServerConfig>) {
+    tokio::spawn(async move {
+        if let Err(e) = handle_connection(stream, sessions, config).await {
+            log_error!("Connection handling error: {}", e);
+        }
+    });
+}
+
+/// Check the token sent as `Authorization: Bearer <token>` or `?token=<token>`
+fn authorize(head: &RequestHead, token: &str) -> bool {
+    let header = head.header("authorization").and_then(|value| value.strip_prefix("Bearer "));
+    let given = header.map(str::to_string).or_else(|| head.query_param("token"));
+    given.is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+/// Compare without exiting early, so response time doesn't leak the token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// How long a path at the end of the output waits for the rest of it
+const LINK_FLUSH_DELAY: Duration = Duration::from_millis(30);
+
+/// Version of the WebSocket protocol, bumped on incompatible changes
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Protocol features clients can check for in `/info`
+const CAPABILITIES: &[&str] = &[
+    "profiles",
+    "env_policy",
+    "login_env",
+    "shell_integration",
+    "linkify",
+    "teardown",
+    "list_sessions",
+    "list_shells",
+    "busy",
+    "get_env",
+    "stats",
+    "set_log_level",
+    "shared_sessions",
+    "restore",
+    "namespaces",
+    "text_output",
+    "encoding",
+    "paste",
+];
+
+/// Reply to `GET /info`
+#[derive(Debug, Serialize)]
+struct ServerInfo {
+    name: &'static str,
+    version: &'static str,
+    protocol: u32,
+    pid: u32,
+    uptime_secs: u64,
+    sessions: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_sessions: Option<usize>,
+    capabilities: &'static [&'static str],
+}
+
+/// Answer a plain HTTP request on the WebSocket listener.
+/// `/health` is open so liveness can be probed without the token,
+/// `/terminal` serves the browser terminal when enabled.
+async fn serve_http(
+    stream: &mut Box<dyn Connection>,
+    head: &RequestHead,
+    authorized: bool,
+    sessions: &SessionRegistry,
+    config: &ServerConfig,
+) -> std::io::Result<()> {
+    let json = "application/json";
+    let web = &config.settings.web;
+    if web.enabled && head.method == "GET" {
+        let html = "text/html; charset=utf-8";
+        match head.path.as_str() {
+            "/terminal" if authorized => return http::respond(stream, "200 OK", html, &web::terminal_page(web)).await,
+            "/terminal" => return http::respond(stream, "401 Unauthorized", html, web::token_form()).await,
+            path => {
+                if let Some((content_type, body)) = web::asset(path) {
+                    return http::respond(stream, "200 OK", content_type, body).await;
+                }
+            }
+        }
     }
+
+    let (status, body) = match (head.method.as_str(), head.path.as_str()) {
+        ("GET", "/health") => ("200 OK", r#"{"status":"ok"}"#.to_string()),
+        ("GET", "/info") if !authorized => ("401 Unauthorized", r#"{"error":"unauthorized"}"#.to_string()),
+        ("GET", "/info") => {
+            let info = ServerInfo {
+                name: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+                protocol: PROTOCOL_VERSION,
+                pid: std::process::id(),
+                uptime_secs: config.started.elapsed().as_secs(),
+                sessions: sessions.count(),
+                max_sessions: config.settings.limits.max_sessions,
+                capabilities: CAPABILITIES,
+            };
+            ("200 OK", serde_json::to_string(&info).unwrap_or_default())
+        }
+        ("GET", "/restorable") if !authorized => ("401 Unauthorized", r#"{"error":"unauthorized"}"#.to_string()),
+        ("GET", "/restorable") => {
+            let namespace = head.query_param("namespace");
+            let restorable = config.store.as_ref().map(|store| store.restorable(namespace.as_deref())).unwrap_or_default();
+            ("200 OK", serde_json::to_string(&restorable).unwrap_or_default())
+        }
+        (_, "/health" | "/info" | "/restorable") => ("405 Method Not Allowed", r#"{"error":"method not allowed"}"#.to_string()),
+        _ => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+    };
+    http::respond(stream, status, json, &body).await
 }
 
 /// Handle a single WebSocket connection
 async fn handle_connection(
-    stream: tokio::net::TcpStream,
+    stream: Box<dyn Connection>,
+    sessions: SessionRegistry,
+    config: Arc<ServerConfig>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Upgrade to WebSocket
-    let ws_stream = accept_async(stream).await?;
+    let settings = &config.settings;
+    let mut stream = stream;
+
+    // Plain HTTP requests are answered here, upgrades continue as WebSocket
+    let (head, buffered) = http::read_head(&mut stream).await?;
+    let authorized = settings.auth.token.as_deref().is_none_or(|token| authorize(&head, token));
+    if !head.is_websocket_upgrade() {
+        log_debug!("HTTP {} {}", head.method, head.path);
+        return Ok(serve_http(&mut stream, &head, authorized, &sessions, &config).await?);
+    }
+    if !authorized {
+        http::respond(&mut stream, "401 Unauthorized", "text/plain", "Unauthorized\n").await?;
+        return Err("WebSocket client sent no valid token".into());
+    }
+
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(settings.limits.max_message_bytes),
+        max_frame_size: Some(settings.limits.max_message_bytes),
+        ..WebSocketConfig::default()
+    };
+    let stream: Box<dyn Connection> = Box::new(http::Replay::new(buffered, stream));
+    let ws_stream = tokio_tungstenite::accept_async_with_config(stream, Some(ws_config)).await?;
     
     log_info!("WebSocket connection established");
     
@@ -117,201 +588,733 @@ async fn handle_connection(
     let (ws_sender, mut ws_receiver) = ws_stream.split();
     let ws_sender = Arc::new(TokioMutex::new(ws_sender));
     
-    // Wait for first message (should be init command)
-    let mut shell_type: Option<String> = None;
-    let mut shell_args: Option<Vec<String>> = None;
-    let mut cwd: Option<String> = None;
-    let mut env: Option<std::collections::HashMap<String, String>> = None;
-    let mut first_msg_processed = false;
+    // The first message attaches to a running session or starts one (init or defaults)
+    let first = match ws_receiver.next().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str::<Command>(&text).ok(),
+        _ => None,
+    };
+    let (shared, client, role) = match first {
+        Some(Command::Attach { session, role, namespace, output, invalid_utf8 }) => {
+            let role = role.unwrap_or_default();
+            let format = OutputFormat { mode: output.unwrap_or_default(), invalid_utf8: invalid_utf8.unwrap_or_default() };
+            // Sessions of other namespaces don't exist for this client
+            let shared = sessions.shared(session).filter(|shared| shared.settings.launch.namespace == namespace);
+            let attached = shared.and_then(|shared| {
+                let attached = shared.attach(Arc::clone(&ws_sender), role, format)?;
+                Some((shared, attached))
+            });
+            let Some((shared, attached)) = attached else {
+                let message = format!("No running session {}", session);
+                let event = Event::Error { code: "session_not_found".to_string(), message: message.clone() };
+                let _ = send_event(&ws_sender, &event).await;
+                return Err(message.into());
+            };
+            log_info!(ctx: shared.log_ctx, "Client {} attached as {:?}", attached.client, role);
+            if let Some(displaced) = attached.displaced {
+                let _ = send_event(&displaced, &Event::ControlChanged { controlling: false }).await;
+            }
+            // Catch up on state reported before the client attached
+            if let Some(info) = sessions.get(session) {
+                if let Some(cwd) = info.cwd {
+                    let in_vault = is_inside_vault(&cwd, shared.settings.launch.vault_root.as_deref());
+                    let _ = send_event(&ws_sender, &Event::CwdChanged { cwd, in_vault }).await;
+                }
+                if let Some(title) = info.title {
+                    let _ = send_event(&ws_sender, &Event::TitleChanged { title }).await;
+                }
+            }
+            (shared, attached.client, role)
+        }
+        first => match start_session(first, &ws_sender, &sessions, &config).await {
+            Ok((shared, client)) => (shared, client, Role::Writer),
+            Err((code, message)) => {
+                // Tell the client why, instead of just dropping the connection
+                let event = Event::Error { code: code.to_string(), message: message.clone() };
+                let _ = send_event(&ws_sender, &event).await;
+                return Err(message.into());
+            }
+        },
+    };
+    let log_ctx = shared.log_ctx;
+    
+    let attached = Event::Attached {
+        session: shared.id,
+        client,
+        role,
+        controlling: shared.is_controlling(client),
+        sharing: shared.settings.sharing,
+        size_policy: shared.settings.size_policy,
+        clients: shared.client_count(),
+        encoding: transcode::name(shared.encoding()),
+        restore_key: config.store.as_ref().map(|store| store.key(shared.id)),
+    };
+    let _ = send_event(&ws_sender, &attached).await;
     
-    if let Some(Ok(Message::Text(text))) = ws_receiver.next().await {
-        if let Ok(Command::Init { shell_type: st, shell_args: sa, cwd: c, env: e }) = serde_json::from_str::<Command>(&text) {
-            log_info!("Received init command, shell_type: {:?}, shell_args: {:?}, cwd: {:?}", st, sa, c);
-            shell_type = st;
-            shell_args = sa;
-            cwd = c;
-            env = e;
-            first_msg_processed = true;
+    // Message handling loop, bounded by the profile's lifetime limit.
+    // Errors end the loop rather than return, so the client is always detached.
+    loop {
+        let next = match shared.deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, ws_receiver.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    log_info!(ctx: log_ctx, "Session reached its lifetime limit");
+                    let lifetime = shared.settings.lifetime.unwrap_or_default();
+                    let event = Event::Error {
+                        code: "session_expired".to_string(),
+                        message: format!("Session closed after {}s (profile limit)", lifetime.as_secs()),
+                    };
+                    let _ = send_event(&ws_sender, &event).await;
+                    break;
+                }
+            },
+            None => ws_receiver.next().await,
+        };
+        let Some(msg_result) = next else {
+            break;
+        };
+        match msg_result {
+            Ok(msg) => {
+                log_debug!(ctx: log_ctx, "Received message type: {:?}", std::mem::discriminant(&msg));
+                
+                match msg {
+                    Message::Text(text) => {
+                        // Try to parse as JSON command
+                        if let Ok(cmd) = serde_json::from_str::<Command>(&text) {
+                            log_debug!(ctx: log_ctx, "Parsed command: {}", cmd.kind());
+                            if let Err(e) = handle_command(cmd, &shared, client, &ws_sender, &sessions, &config).await {
+                                log_error!(ctx: log_ctx, "Command failed: {}", e);
+                                break;
+                            }
+                        } else {
+                            // Plain text input, write to PTY
+                            log_debug!(ctx: log_ctx, "Received text input: {} bytes", text.len());
+                            write_input(&shared, client, text.as_bytes()).await;
+                        }
+                    }
+                    Message::Binary(data) => {
+                        // Binary input, write to PTY
+                        log_debug!(ctx: log_ctx, "Received binary input: {} bytes", data.len());
+                        write_input(&shared, client, &data).await;
+                    }
+                    Message::Close(_) => {
+                        log_info!(ctx: log_ctx, "Client closed connection");
+                        break;
+                    }
+                    Message::Ping(data) => {
+                        // Respond to Ping
+                        let mut sender = ws_sender.lock().await;
+                        if sender.send(Message::Pong(data)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Message::Pong(_) => {
+                        // Ignore Pong
+                    }
+                    _ => {
+                        log_debug!(ctx: log_ctx, "Ignored message type");
+                    }
+                }
+            }
+            Err(e) => {
+                log_error!(ctx: log_ctx, "Message receive error: {}", e);
+                break;
+            }
         }
     }
     
-    if !first_msg_processed {
+    log_info!(ctx: log_ctx, "WebSocket connection closed");
+    let detached = shared.detach(client);
+    if let Some(sender) = detached.new_controller {
+        let _ = send_event(&sender, &Event::ControlChanged { controlling: true }).await;
+    }
+    if detached.remaining > 0 {
+        log_info!(ctx: log_ctx, "Client {} detached, {} still attached", client, detached.remaining);
+        if let Some((cols, rows)) = detached.resize {
+            resize_pty(&shared, cols, rows).await;
+        }
+        return Ok(());
+    }
+    end_session(&shared, &sessions, &config).await
+}
+
+/// Spawn the shell for a new session from the first message (init or defaults)
+/// and attach the connecting client as its writer
+async fn start_session(
+    first: Option<Command>,
+    ws_sender: &WsSender,
+    sessions: &SessionRegistry,
+    config: &ServerConfig,
+) -> Result<(Arc<SharedSession>, u64), (&'static str, String)> {
+    let settings = &config.settings;
+    let mut options = SpawnOptions { env_policy: settings.env_policy.clone(), ..SpawnOptions::default() };
+    let mut session_settings = SessionSettings {
+        sharing: settings.sharing.mode,
+        size_policy: settings.sharing.size_policy,
+        scrollback_bytes: if config.store.is_some() { settings.persistence.scrollback_bytes } else { 0 },
+        client_queue: settings.buffers.client_queue,
+        input_queue: settings.limits.max_queued_input,
+        ..SessionSettings::default()
+    };
+    let mut linkify = false;
+    let mut format = OutputFormat::default();
+    let mut profile: Option<String> = None;
+    let mut profile_max_sessions: Option<usize> = None;
+    let mut restored: Option<SavedSession> = None;
+    
+    if let Some(Command::Init(init)) = first {
+        let InitRequest { shell_type: mut st, shell_args: mut sa, cwd: mut c, env: mut e, vault_root: mut v, teardown: t, linkify: l, login: lo, interactive: i, env_policy: ep, profile: mut p, sharing: sh, size_policy: sp, restore: r, namespace: ns, output: o, invalid_utf8: iu, encoding: mut enc } = *init;
+        log_info!("Received init command, profile: {:?}, shell_type: {:?}, shell_args: {:?}, cwd: {:?}", p, st, sa, c);
+        
+        // A restored session starts from what was saved, init fields override it
+        if let Some(key) = r {
+            let Some(saved) = config.store.as_ref().and_then(|store| store.get(&key, ns.as_deref())) else {
+                return Err(("restore_not_found", format!("No restorable session {}", key)));
+            };
+            p = p.or_else(|| saved.launch.profile.clone());
+            st = st.or_else(|| saved.launch.shell_type.clone());
+            sa = sa.or_else(|| saved.launch.shell_args.clone());
+            v = v.or_else(|| saved.launch.vault_root.clone());
+            enc = enc.or_else(|| saved.launch.encoding.clone());
+            // The saved directory may be gone by now
+            c = c.or_else(|| saved.cwd.clone().filter(|cwd| std::path::Path::new(cwd).is_dir()));
+            if let Some(mut env) = saved.launch.env.clone() {
+                env.extend(e.unwrap_or_default());
+                e = Some(env);
+            }
+            restored = Some(saved);
+        }
+        
+        if let Some(label) = enc {
+            session_settings.encoding = transcode::lookup(&label).map_err(|e| ("unknown_encoding", e))?;
+        }
+        
+        if let Some(name) = p {
+            match resolve_profile(config, &name, v.as_deref()) {
+                Ok((profile_options, limits)) => {
+                    options = profile_options;
+                    session_settings.lifetime = limits.max_lifetime_secs.map(Duration::from_secs);
+                    profile_max_sessions = limits.max_sessions;
+                    profile = Some(name);
+                }
+                Err(e) => return Err((e.code(), e.to_string())),
+            }
+        }
+        
+        // Fields sent in init take precedence over the profile
+        if st.is_some() {
+            options.shell_type = st;
+        }
+        if sa.is_some() {
+            options.shell_args = sa;
+        }
+        if c.is_some() {
+            options.cwd = c;
+        }
+        if let Some(env) = e {
+            options.env.get_or_insert_with(HashMap::new).extend(env);
+        }
+        if let Some(login) = lo {
+            options.mode.login = login;
+        }
+        if let Some(interactive) = i {
+            options.mode.interactive = interactive;
+        }
+        if let Some(policy) = ep {
+            options.env_policy = policy;
+        }
+        if let Some(sharing) = sh {
+            session_settings.sharing = sharing;
+        }
+        if let Some(size_policy) = sp {
+            session_settings.size_policy = size_policy;
+        }
+        session_settings.launch.vault_root = v;
+        session_settings.launch.namespace = ns;
+        session_settings.teardown = t.unwrap_or_default();
+        linkify = l.unwrap_or(false);
+        format = OutputFormat { mode: o.unwrap_or_default(), invalid_utf8: iu.unwrap_or_default() };
+    } else {
         log_info!("No init command received, using default config");
     }
     
+    // Held until the session is registered, a failed spawn gives the slot back
+    let reservation = match sessions.reserve(profile.as_deref(), settings.limits.max_sessions, profile_max_sessions) {
+        Ok(reservation) => reservation,
+        Err(SessionLimit::Global(max)) => {
+            return Err(("session_limit_reached", format!("Session limit reached ({} running)", max)));
+        }
+        Err(SessionLimit::Profile(max)) => {
+            let e = ProfileError::LimitReached(profile.unwrap_or_default(), max);
+            return Err((e.code(), e.to_string()));
+        }
+    };
+    options.base_env = config.base_env.get().await.vars();
+    
     // Create PTY session (reader and writer are independent, no lock needed)
-    let (pty_session, pty_reader, pty_writer) = PtySession::new(
-        80, 
-        24, 
-        shell_type.as_deref(), 
-        shell_args.as_ref().map(|v| v.as_slice()),
-        cwd.as_deref(),
-        env.as_ref()
-    )?;
-    let pty_session = Arc::new(TokioMutex::new(pty_session));
+    let spawn_started = std::time::Instant::now();
+    let size = (settings.terminal.cols, settings.terminal.rows);
+    let pty_session = PtySession::new(size.0, size.1, &options).map_err(|e| {
+        let code = match e.downcast_ref::<ShellSpecError>() {
+            Some(spec_error) => spec_error.code(),
+            None => "spawn_failed",
+        };
+        (code, e.to_string())
+    });
+    let (pty_session, pty_reader, pty_writer) = match pty_session {
+        Ok(session) => session,
+        Err(e) => {
+            METRICS.spawn_failed();
+            return Err(e);
+        }
+    };
+    let shell_pid = pty_session.pid();
+    session_settings.launch = Launch {
+        profile: profile.clone(),
+        shell_type: options.shell_type.clone(),
+        shell_args: options.shell_args.clone(),
+        env: options.env.clone(),
+        vault_root: session_settings.launch.vault_root.take(),
+        namespace: session_settings.launch.namespace.take(),
+        encoding: session_settings.encoding.map(|encoding| encoding.name().to_string()),
+    };
+    let session_id = reservation.register(
+        shell_pid,
+        options.shell_type.clone(),
+        options.cwd.clone(),
+        session_settings.launch.namespace.clone(),
+    );
+    let log_ctx = logging::Context { session: Some(session_id), pid: shell_pid };
+    let session_metrics = METRICS.session_started(session_id, spawn_started.elapsed());
     
-    // Wrap reader and writer in Arc<Mutex<>> for sharing between tasks
-    let pty_reader = Arc::new(Mutex::new(pty_reader));
-    let pty_writer = Arc::new(Mutex::new(pty_writer));
+    log_info!(ctx: log_ctx, "PTY session created, shell_type: {:?}", options.shell_type);
     
-    log_info!("PTY session created, shell_type: {:?}", shell_type);
+    let mut linkifier = match session_settings.launch.vault_root.as_deref() {
+        Some(root) if linkify => Linkifier::new(root),
+        _ => None,
+    };
+    if linkify && linkifier.is_none() {
+        log_warn!(ctx: log_ctx, "Hyperlinking disabled, vault root not found: {:?}", session_settings.launch.vault_root);
+    }
     
-    // Clone for read task
-    let ws_sender_for_read = Arc::clone(&ws_sender);
-    let pty_reader_for_read = Arc::clone(&pty_reader);
+    let shared = Arc::new(SharedSession::new(
+        session_id,
+        pty_session,
+        pty_writer,
+        size,
+        session_settings,
+        session_metrics,
+        log_ctx,
+    ));
+    // Attach before output is read so the first client sees all of it
+    let client = shared.attach(Arc::clone(ws_sender), Role::Writer, format).expect("new session is open").client;
+    sessions.set_shared(session_id, Arc::clone(&shared));
+    
+    // Bring back the saved title and output before the new shell's first prompt
+    if let (Some(saved), Some(store)) = (restored, &config.store) {
+        store.remove(&saved.key);
+        log_info!(ctx: log_ctx, "Restored session {}", saved.key);
+        if let Some(title) = saved.title {
+            sessions.set_title(session_id, &title);
+            broadcast_event(&shared, &Event::TitleChanged { title });
+        }
+        if !saved.scrollback.is_empty() {
+            let mut replay = saved.scrollback.into_bytes();
+            // Leave the alternate screen and turn off mouse reporting, bracketed paste,
+            // application cursor keys and attributes the saved output may have left set,
+            // the new shell doesn't know about them
+            replay.extend_from_slice(b"\x1b[?1049l\x1b[?1000l\x1b[?1002l\x1b[?1003l\x1b[?1006l\x1b[?2004l\x1b[?1l\x1b[0m\r\n");
+            shared.record_output(&replay);
+            broadcast_output(&shared, &replay);
+        }
+    }
     
-    // Clone for shell integration injection
-    let pty_writer_for_init = Arc::clone(&pty_writer);
-    let shell_type_for_init = shell_type.clone();
+    // Clone for read task
+    let shared_for_read = Arc::clone(&shared);
+    let pty_reader = Arc::new(Mutex::new(pty_reader));
+    let sessions_for_read = sessions.clone();
+    let read_buffer_size = settings.buffers.pty_read;
+    let mut current_cwd = options.cwd.clone();
     
     // Start PTY output read task
     let read_task = tokio::spawn(async move {
+        let shared = shared_for_read;
         let mut first_output = true;
+        let mut osc_parser = OscParser::new();
+        let mut boundary = Utf8Boundary::default();
+        let mut decoder = OutputDecoder::new(shared.encoding());
+        let mut paste_mode = BracketedPasteMode::default();
         
         loop {
             // Read PTY output in blocking task
-            let reader = Arc::clone(&pty_reader_for_read);
-            let result = tokio::task::spawn_blocking(move || -> Result<(Vec<u8>, usize), String> {
+            let reader = Arc::clone(&pty_reader);
+            let mut read = tokio::task::spawn_blocking(move || -> Result<(Vec<u8>, usize), String> {
                 let mut reader = reader.lock().unwrap();
-                let mut local_buf = vec![0u8; 8192];
+                let mut local_buf = vec![0u8; read_buffer_size];
                 match reader.read(&mut local_buf) {
                     Ok(n) => Ok((local_buf, n)),
                     Err(e) => Err(e.to_string()),
                 }
-            }).await;
+            });
+            // A path held back for the next read is sent unlinked once the program pauses
+            let result = loop {
+                if !linkifier.as_ref().is_some_and(Linkifier::has_pending) {
+                    break (&mut read).await;
+                }
+                tokio::select! {
+                    result = &mut read => break result,
+                    _ = tokio::time::sleep(LINK_FLUSH_DELAY) => {
+                        let output = run_linkifier(&mut linkifier, None, current_cwd.clone(), log_ctx).await;
+                        send_output(&shared, boundary.complete(output));
+                    }
+                }
+            };
             
             match result {
-                Ok(Ok((data, n))) if n > 0 => {
-                    log_debug!("Read PTY output: {} bytes", n);
-                    // Send to WebSocket
-                    let mut sender = ws_sender_for_read.lock().await;
-                    if let Err(e) = sender.send(Message::Binary(data[..n].to_vec())).await {
-                        log_error!("Failed to send PTY output: {}", e);
-                        break;
-                    }
-                    drop(sender);
-                    
-                    // After first output, inject Shell Integration script
+                Ok(Ok((mut data, n))) if n > 0 => {
+                    log_debug!(ctx: log_ctx, "Read PTY output: {} bytes", n);
+                    METRICS.pty_read(n);
                     if first_output {
                         first_output = false;
-                        if let Some(ref st) = shell_type_for_init {
-                            if let Some(script) = crate::shell::get_shell_integration_script(st) {
-                                let mut writer = pty_writer_for_init.lock().unwrap();
-                                if let Err(e) = writer.write(script.as_bytes()) {
-                                    log_error!("Failed to send Shell Integration script: {}", e);
-                                } else {
-                                    log_debug!("Shell Integration script sent");
-                                }
-                            }
+                        METRICS.first_output(spawn_started.elapsed());
+                    }
+                    
+                    // Everything below works on UTF-8
+                    data.truncate(n);
+                    decoder.follow(shared.encoding());
+                    let data = decoder.decode(data);
+                    shared.set_bracketed_paste(paste_mode.feed(&data));
+                    
+                    // Track state reported through OSC sequences
+                    let events: Vec<Event> = osc_parser.feed(&data).into_iter().map(|event| match event {
+                        OscEvent::Cwd(cwd) => {
+                            log_debug!(ctx: log_ctx, "Cwd changed: {}", cwd);
+                            sessions_for_read.set_cwd(session_id, &cwd);
+                            current_cwd = Some(cwd.clone());
+                            let in_vault = is_inside_vault(&cwd, shared.settings.launch.vault_root.as_deref());
+                            Event::CwdChanged { cwd, in_vault }
+                        }
+                        OscEvent::Title(title) => {
+                            sessions_for_read.set_title(session_id, &title);
+                            Event::TitleChanged { title }
                         }
+                        OscEvent::Notify { title, body } => Event::Notify { title, body },
+                        OscEvent::Bell => Event::Bell,
+                    }).collect();
+                    
+                    // Optionally hyperlink vault paths
+                    let output = match linkifier {
+                        Some(_) => run_linkifier(&mut linkifier, Some(data), current_cwd.clone(), log_ctx).await,
+                        None => data,
+                    };
+                    // Frames end on character boundaries
+                    send_output(&shared, boundary.complete(output));
+                    
+                    for event in &events {
+                        broadcast_event(&shared, event);
                     }
                 }
                 Ok(Ok(_)) => {
-                    // EOF
-                    log_info!("PTY output ended");
+                    // EOF, send what a partial path or character held back
+                    let rest = run_linkifier(&mut linkifier, None, current_cwd.clone(), log_ctx).await;
+                    let mut rest = boundary.complete(rest);
+                    rest.extend(boundary.flush());
+                    send_output(&shared, rest);
+                    log_info!(ctx: log_ctx, "PTY output ended");
                     break;
                 }
                 Ok(Err(e)) => {
-                    log_error!("PTY output read error: {}", e);
+                    log_error!(ctx: log_ctx, "PTY output read error: {}", e);
                     break;
                 }
                 Err(e) => {
-                    log_error!("PTY read task error: {}", e);
+                    log_error!(ctx: log_ctx, "PTY read task error: {}", e);
                     break;
                 }
             }
         }
     });
+    shared.add_task(read_task.abort_handle());
     
-    // Clone for write
-    let pty_writer_for_write = Arc::clone(&pty_writer);
+    // Watch the foreground process group for live tab titles
+    let foreground_task = tokio::spawn(watch_foreground(
+        Arc::clone(&shared),
+        shell_pid,
+        Duration::from_millis(settings.buffers.foreground_poll_ms),
+    ));
+    shared.add_task(foreground_task.abort_handle());
     
-    // Message handling loop
-    while let Some(msg_result) = ws_receiver.next().await {
-        match msg_result {
-            Ok(msg) => {
-                log_debug!("Received message type: {:?}", std::mem::discriminant(&msg));
-                
-                match msg {
-                    Message::Text(text) => {
-                        // Try to parse as JSON command
-                        if let Ok(cmd) = serde_json::from_str::<Command>(&text) {
-                            log_debug!("Parsed command: {:?}", cmd);
-                            handle_command(cmd, &pty_session).await?;
-                        } else {
-                            // Plain text input, write to PTY
-                            log_debug!("Received text input: {} bytes", text.len());
-                            let mut writer = pty_writer_for_write.lock().unwrap();
-                            if let Err(e) = writer.write(text.as_bytes()) {
-                                log_error!("Failed to write to PTY: {}", e);
-                            }
-                        }
-                    }
-                    Message::Binary(data) => {
-                        // Binary input, write to PTY
-                        log_debug!("Received binary input: {} bytes", data.len());
-                        let mut writer = pty_writer_for_write.lock().unwrap();
-                        if let Err(e) = writer.write(&data) {
-                            log_error!("Failed to write to PTY: {}", e);
-                        }
-                    }
-                    Message::Close(_) => {
-                        log_info!("Client closed connection");
-                        break;
-                    }
-                    Message::Ping(data) => {
-                        // Respond to Ping
-                        let mut sender = ws_sender.lock().await;
-                        sender.send(Message::Pong(data)).await?;
-                    }
-                    Message::Pong(_) => {
-                        // Ignore Pong
-                    }
-                    _ => {
-                        log_debug!("Ignored message type");
-                    }
-                }
-            }
-            Err(e) => {
-                log_error!("Message receive error: {}", e);
-                break;
-            }
-        }
+    Ok((shared, client))
+}
+
+/// Tear down a session once its last client has left
+async fn end_session(shared: &SharedSession, sessions: &SessionRegistry, config: &ServerConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let log_ctx = shared.log_ctx;
+    shared.stop_tasks();
+    if let Some(store) = &config.store {
+        store.session_ended(shared, sessions);
     }
     
-    log_info!("WebSocket connection closed");
-    
-    // Terminate PTY process
-    let mut pty = pty_session.lock().await;
-    let _ = pty.kill();
-    drop(pty); // Release lock
-    
-    // Wait for read task to finish
-    let _ = read_task.await;
-    
+    // Terminate PTY process group and descendants (blocks while waiting for exit)
+    let pty_for_teardown = Arc::clone(&shared.pty);
+    let teardown = shared.settings.teardown.clone();
+    let report = tokio::task::spawn_blocking(move || {
+        pty_for_teardown.blocking_lock().terminate(&teardown)
+    }).await?;
+    if report.survivors.is_empty() {
+        log_info!(ctx: log_ctx, "Session terminated, signals sent: {:?}", report.signals_sent);
+    } else {
+        log_error!(ctx: log_ctx, "Processes survived teardown: {:?}", report.survivors);
+    }
+    sessions.unregister(shared.id);
+    METRICS.session_ended(shared.id);
     Ok(())
 }
 
+/// Transform output with the session's linkifier off the runtime thread, its path
+/// lookups hit the filesystem. `None` flushes a held back path.
+async fn run_linkifier(linkifier: &mut Option<Linkifier>, data: Option<Vec<u8>>, cwd: Option<String>, log_ctx: logging::Context) -> Vec<u8> {
+    let Some(mut active) = linkifier.take() else {
+        return data.unwrap_or_default();
+    };
+    let result = tokio::task::spawn_blocking(move || {
+        let output = match data {
+            Some(data) => active.transform(&data, cwd.as_deref()),
+            None => active.flush(cwd.as_deref()),
+        };
+        (active, output)
+    }).await;
+    match result {
+        Ok((active, output)) => {
+            *linkifier = Some(active);
+            output
+        }
+        Err(e) => {
+            log_error!(ctx: log_ctx, "Hyperlinking failed, disabled for this session: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Keep output for restoring and send it to every client
+fn send_output(shared: &SharedSession, output: Vec<u8>) {
+    if !output.is_empty() {
+        shared.record_output(&output);
+        broadcast_output(shared, &output);
+        shared.metrics.record_out(output.len());
+    }
+}
+
+/// Send terminal output to every client of a session in its output format
+fn broadcast_output(shared: &SharedSession, data: &[u8]) {
+    shared.broadcast_output(|format| match format.mode {
+        OutputMode::Binary => Message::Binary(data.to_vec()),
+        OutputMode::Text => {
+            let event = Event::Output { data: to_text(data, format.invalid_utf8) };
+            Message::Text(serde_json::to_string(&event).unwrap_or_default())
+        }
+    });
+}
+
+/// Send an event to every client of a session
+fn broadcast_event(shared: &SharedSession, event: &Event) {
+    match serde_json::to_string(event) {
+        Ok(text) => shared.broadcast(Message::Text(text)),
+        Err(e) => log_error!(ctx: shared.log_ctx, "Failed to encode event: {}", e),
+    }
+}
+
+/// Write client input to the PTY, dropped when the client doesn't control input
+async fn write_input(shared: &SharedSession, client: u64, data: &[u8]) {
+    let result = shared.write_input(client, data).await;
+    match result {
+        Ok(Input::Written { resize }) => {
+            // The last writer may decide the size
+            if let Some((cols, rows)) = resize {
+                resize_pty(shared, cols, rows).await;
+            }
+        }
+        Ok(Input::Dropped) => log_debug!(ctx: shared.log_ctx, "Dropped input from client {} without control", client),
+        Err(e) => log_error!(ctx: shared.log_ctx, "Failed to write to PTY: {}", e),
+    }
+}
+
+/// Apply a PTY size chosen by the session's size policy
+async fn resize_pty(shared: &SharedSession, cols: u16, rows: u16) {
+    log_info!(ctx: shared.log_ctx, "PTY resized to {}x{}", cols, rows);
+    let result = shared.pty.lock().await.resize(cols, rows).map_err(|e| e.to_string());
+    if let Err(e) = result {
+        log_error!(ctx: shared.log_ctx, "Failed to resize PTY: {}", e);
+    }
+}
+
+/// Look up a profile, returns its spawn options and limits
+fn resolve_profile(
+    config: &ServerConfig,
+    name: &str,
+    vault_root: Option<&str>,
+) -> Result<(SpawnOptions, ProfileLimits), ProfileError> {
+    let profile = config.settings.profiles.get(name).ok_or_else(|| ProfileError::NotFound(name.to_string()))?;
+    let options = profile.spawn_options(name, vault_root, &config.settings.env_policy)?;
+    Ok((options, profile.limits.clone()))
+}
+
+/// Poll the PTY foreground process group and report changes
+async fn watch_foreground(shared: Arc<SharedSession>, shell_pid: Option<u32>, poll_interval: Duration) {
+    let log_ctx = shared.log_ctx;
+    let mut interval = tokio::time::interval(poll_interval);
+    let mut last_pgid: Option<u32> = None;
+
+    loop {
+        interval.tick().await;
+
+        let pgid = shared.pty.lock().await.foreground_pgid();
+        if pgid.is_none() || pgid == last_pgid {
+            continue;
+        }
+        last_pgid = pgid;
+
+        // Without /proc this runs ps, keep it off the runtime thread
+        let process = tokio::task::spawn_blocking(move || pgid.and_then(process_info)).await;
+        let Ok(Some(process)) = process else {
+            continue;
+        };
+        log_debug!(ctx: log_ctx, "Foreground process changed: {} ({})", process.name, process.pid);
+
+        let is_shell = Some(process.pid) == shell_pid;
+        broadcast_event(&shared, &Event::ForegroundChanged { process, is_shell });
+    }
+}
+
 /// Handle command message
 async fn handle_command(
     cmd: Command,
-    pty_session: &Arc<TokioMutex<PtySession>>,
+    shared: &SharedSession,
+    client: u64,
+    ws_sender: &WsSender,
+    sessions: &SessionRegistry,
+    config: &ServerConfig,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let log_ctx = shared.log_ctx;
+    // Session lists only show the client's namespace
+    let namespace = shared.settings.launch.namespace.as_deref();
     match cmd {
         Command::Resize { cols, rows } => {
-            log_info!("Received resize command: {}x{}", cols, rows);
-            let mut pty = pty_session.lock().await;
-            pty.resize(cols, rows)?;
+            log_info!(ctx: log_ctx, "Received resize command from client {}: {}x{}", client, cols, rows);
+            // The PTY follows the size policy over all clients' sizes
+            if let Some((cols, rows)) = shared.set_client_size(client, cols, rows) {
+                resize_pty(shared, cols, rows).await;
+            }
         }
         Command::Env { cwd, env } => {
-            log_info!("Received env command: cwd={:?}, env={:?}", cwd, env);
+            let names: Vec<&String> = env.iter().flat_map(|env| env.keys()).collect();
+            log_info!(ctx: log_ctx, "Received env command: cwd={:?}, env names={:?}", cwd, names);
             // Note: Environment variables and working directory should be set at PTY creation
             // This is just logged here, actual implementation needs to handle at creation time
         }
-        Command::Init { .. } => {
-            log_info!("Received init command (already handled at connection establishment)");
+        Command::Init(_) => {
+            log_info!(ctx: log_ctx, "Received init command (already handled at connection establishment)");
             // Init command already handled at connection establishment, ignore here
         }
+        Command::Attach { .. } => {
+            log_info!(ctx: log_ctx, "Received attach command (only accepted as the first message)");
+        }
+        Command::ListRestorable => {
+            let sessions = config.store.as_ref().map(|store| store.restorable(namespace)).unwrap_or_default();
+            send_event(ws_sender, &Event::Restorable { sessions }).await?;
+        }
+        Command::DiscardRestorable { key } => {
+            if let Some(store) = &config.store {
+                if store.get(&key, namespace).is_some() && store.remove(&key) {
+                    log_info!(ctx: log_ctx, "Discarded restorable session {}", key);
+                }
+            }
+            let sessions = config.store.as_ref().map(|store| store.restorable(namespace)).unwrap_or_default();
+            send_event(ws_sender, &Event::Restorable { sessions }).await?;
+        }
+        Command::TakeControl => match shared.take_control(client) {
+            TakeControl::Denied => {
+                let event = Event::Error { code: "read_only".to_string(), message: "Observers can't take control".to_string() };
+                send_event(ws_sender, &event).await?;
+            }
+            TakeControl::Taken { displaced } => {
+                log_info!(ctx: log_ctx, "Client {} took control", client);
+                if let Some(displaced) = displaced {
+                    let _ = send_event(&displaced, &Event::ControlChanged { controlling: false }).await;
+                }
+                send_event(ws_sender, &Event::ControlChanged { controlling: true }).await?;
+            }
+        },
+        Command::Paste { data } => {
+            let settings = &config.settings.paste;
+            let bracketed = shared.bracketed_paste();
+            let paste = paste::prepare(&data, settings.control_chars, bracketed);
+            log_debug!(ctx: log_ctx, "Pasting {} bytes, bracketed: {}", paste.len(), bracketed);
+            let delay = Duration::from_millis(settings.chunk_delay_ms);
+            match shared.write_paste(client, paste.as_bytes(), settings.chunk_bytes, delay).await {
+                Ok(Input::Written { resize: Some((cols, rows)) }) => resize_pty(shared, cols, rows).await,
+                Ok(Input::Written { resize: None }) => {}
+                Ok(Input::Dropped) => log_debug!(ctx: log_ctx, "Dropped paste from client {} without control", client),
+                Err(e) => log_error!(ctx: log_ctx, "Failed to write paste to PTY: {}", e),
+            }
+        }
+        Command::SetEncoding { encoding } => {
+            if !shared.is_controlling(client) {
+                let event = Event::Error { code: "read_only".to_string(), message: "Only the controlling writer can switch encoding".to_string() };
+                send_event(ws_sender, &event).await?;
+                return Ok(());
+            }
+            match transcode::lookup(&encoding) {
+                Ok(encoding) => {
+                    shared.set_encoding(encoding);
+                    let encoding = transcode::name(encoding);
+                    log_info!(ctx: log_ctx, "Client {} switched encoding to {}", client, encoding);
+                    broadcast_event(shared, &Event::EncodingChanged { encoding });
+                }
+                Err(message) => {
+                    send_event(ws_sender, &Event::Error { code: "unknown_encoding".to_string(), message }).await?;
+                }
+            }
+        }
+        Command::ListSessions => {
+            let sessions = sessions.list(namespace);
+            send_event(ws_sender, &Event::Sessions { sessions }).await?;
+        }
+        Command::Busy => {
+            let shell_pid = shared.pty.lock().await.pid();
+            // Scanning the process table may spawn ps, keep it off the runtime thread
+            let processes = match shell_pid {
+                Some(pid) => tokio::task::spawn_blocking(move || child_process_tree(pid)).await?,
+                None => Vec::new(),
+            };
+            log_debug!(ctx: log_ctx, "Busy query: {} child processes", processes.len());
+            let event = Event::Busy { busy: !processes.is_empty(), processes };
+            send_event(ws_sender, &event).await?;
+        }
+        Command::ListShells => {
+            // Discovery spawns every shell for its version
+            let shells = tokio::task::spawn_blocking(discover_shells).await?;
+            send_event(ws_sender, &Event::Shells { shells }).await?;
+        }
+        Command::ListProfiles => {
+            let profiles = config.settings.profile_entries();
+            send_event(ws_sender, &Event::Profiles { profiles }).await?;
+        }
+        Command::GetEnv => {
+            let env = shared.pty.lock().await.environment().clone();
+            let (base, error) = match config.base_env.get().await {
+                BaseEnv::Server => ("server", None),
+                BaseEnv::Login(_) => ("login", None),
+                BaseEnv::LoginFailed(e) => ("server", Some(e.clone())),
+            };
+            send_event(ws_sender, &Event::Environment { base, error, env }).await?;
+        }
+        Command::Stats => {
+            send_event(ws_sender, &Event::Stats { stats: METRICS.snapshot() }).await?;
+        }
+        Command::SetLogLevel { level } => {
+            log_info!(ctx: log_ctx, "Log level set to {:?}", level);
+            logging::set_level(level);
+        }
     }
     Ok(())
 }
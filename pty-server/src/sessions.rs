@@ -0,0 +1,223 @@
+// Session Registry
+use crate::shared::SharedSession;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Public information about a running session
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub pid: Option<u32>,
+    pub shell_type: Option<String>,
+    /// Profile the session was started from
+    pub profile: Option<String>,
+    /// Last working directory reported by the shell
+    pub cwd: Option<String>,
+    /// Last title set through OSC 0/2
+    pub title: Option<String>,
+    /// Attached clients
+    pub clients: usize,
+    /// Namespace of the client that started the session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+/// Session limit that stopped a reservation, with its maximum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionLimit {
+    Global(usize),
+    Profile(usize),
+}
+
+#[derive(Default)]
+struct Sessions {
+    running: HashMap<u64, SessionInfo>,
+    /// Profiles of reserved sessions whose shell is still starting
+    starting: HashMap<u64, Option<String>>,
+}
+
+impl Sessions {
+    fn count_profile(&self, profile: &str) -> usize {
+        let running = self.running.values().filter(|s| s.profile.as_deref() == Some(profile)).count();
+        running + self.starting.values().filter(|p| p.as_deref() == Some(profile)).count()
+    }
+}
+
+/// Registry of all sessions served by this process, shared between connections
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<Sessions>>,
+    /// Running sessions clients can attach to
+    shared: Arc<Mutex<HashMap<u64, Arc<SharedSession>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserve a slot for a new session before its shell is spawned, so
+    /// concurrent starts can't exceed the global or the profile's limit
+    pub fn reserve(
+        &self,
+        profile: Option<&str>,
+        max_sessions: Option<usize>,
+        profile_max_sessions: Option<usize>,
+    ) -> Result<Reservation, SessionLimit> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(max) = max_sessions {
+            if sessions.running.len() + sessions.starting.len() >= max {
+                return Err(SessionLimit::Global(max));
+            }
+        }
+        if let (Some(profile), Some(max)) = (profile, profile_max_sessions) {
+            if sessions.count_profile(profile) >= max {
+                return Err(SessionLimit::Profile(max));
+            }
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        sessions.starting.insert(id, profile.map(str::to_string));
+        Ok(Reservation { registry: self.clone(), id })
+    }
+
+    /// Make a registered session available to `attach`
+    pub fn set_shared(&self, id: u64, session: Arc<SharedSession>) {
+        self.shared.lock().unwrap().insert(id, session);
+    }
+
+    /// Session to attach to
+    pub fn shared(&self, id: u64) -> Option<Arc<SharedSession>> {
+        self.shared.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Running sessions in id order
+    pub fn all_shared(&self) -> Vec<Arc<SharedSession>> {
+        let mut shared: Vec<Arc<SharedSession>> = self.shared.lock().unwrap().values().cloned().collect();
+        shared.sort_by_key(|s| s.id);
+        shared
+    }
+
+    /// Remove a session from the registry
+    pub fn unregister(&self, id: u64) {
+        self.sessions.lock().unwrap().running.remove(&id);
+        self.shared.lock().unwrap().remove(&id);
+    }
+
+    /// Information about a single session
+    pub fn get(&self, id: u64) -> Option<SessionInfo> {
+        let mut info = self.sessions.lock().unwrap().running.get(&id).cloned()?;
+        info.clients = self.shared(id).map_or(0, |s| s.client_count());
+        Some(info)
+    }
+
+    /// Update the tracked working directory of a session
+    pub fn set_cwd(&self, id: u64, cwd: &str) {
+        if let Some(info) = self.sessions.lock().unwrap().running.get_mut(&id) {
+            info.cwd = Some(cwd.to_string());
+        }
+    }
+
+    /// Update the tracked title of a session
+    pub fn set_title(&self, id: u64, title: &str) {
+        if let Some(info) = self.sessions.lock().unwrap().running.get_mut(&id) {
+            info.title = Some(title.to_string());
+        }
+    }
+
+    /// Number of running sessions
+    pub fn count(&self) -> usize {
+        self.sessions.lock().unwrap().running.len()
+    }
+
+    /// List the sessions of a namespace ordered by id
+    pub fn list(&self, namespace: Option<&str>) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .running
+            .values()
+            .filter(|s| s.namespace.as_deref() == namespace)
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|s| s.id);
+        for info in &mut sessions {
+            info.clients = self.shared(info.id).map_or(0, |s| s.client_count());
+        }
+        sessions
+    }
+}
+
+/// Slot of a session whose shell is starting, released on drop unless registered
+pub struct Reservation {
+    registry: SessionRegistry,
+    id: u64,
+}
+
+impl Reservation {
+    /// Register the started session in the reserved slot, returns its id
+    pub fn register(
+        self,
+        pid: Option<u32>,
+        shell_type: Option<String>,
+        cwd: Option<String>,
+        namespace: Option<String>,
+    ) -> u64 {
+        let mut sessions = self.registry.sessions.lock().unwrap();
+        let profile = sessions.starting.remove(&self.id).flatten();
+        let info = SessionInfo { id: self.id, pid, shell_type, profile, cwd, title: None, clients: 0, namespace };
+        sessions.running.insert(self.id, info);
+        self.id
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().starting.remove(&self.id);
+    }
+}
+
+/// Check whether a path is inside the vault root
+pub fn is_inside_vault(path: &str, vault_root: Option<&str>) -> bool {
+    match vault_root {
+        Some(root) if !root.is_empty() => {
+            #[cfg(windows)]
+            {
+                // Windows paths are case-insensitive
+                Path::new(&path.to_lowercase()).starts_with(root.to_lowercase())
+            }
+            #[cfg(not(windows))]
+            {
+                Path::new(path).starts_with(root)
+            }
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reservation_counts_toward_limits() {
+        let sessions = SessionRegistry::new();
+        let first = sessions.reserve(Some("build"), Some(3), Some(1)).unwrap();
+        assert_eq!(sessions.reserve(Some("build"), Some(3), Some(1)).err(), Some(SessionLimit::Profile(1)));
+
+        let second = sessions.reserve(None, Some(3), None).unwrap();
+        let id = first.register(Some(1), None, None, None);
+        assert_eq!(sessions.get(id).unwrap().profile.as_deref(), Some("build"));
+        let _third = sessions.reserve(None, Some(3), None).unwrap();
+        assert_eq!(sessions.reserve(None, Some(3), None).err(), Some(SessionLimit::Global(3)));
+
+        // A failed spawn gives its slot back
+        drop(second);
+        assert!(sessions.reserve(None, Some(3), None).is_ok());
+        assert_eq!(sessions.count(), 1);
+    }
+}
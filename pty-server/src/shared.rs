@@ -0,0 +1,491 @@
+// Shared Sessions
+//
+// A session can have several clients, e.g. the same terminal in two Obsidian
+// panes. PTY output and events fan out to every client. Input is accepted
+// from the controlling writer (exclusive sharing) or from every writer
+// (collaborative sharing); observers never write. The PTY size follows the
+// session's size policy over the sizes reported by its clients. Each client
+// has a bounded queue drained by its own task, so a slow client can't hold up
+// the PTY read loop; one that falls too far behind is disconnected. Writes
+// to the PTY block while the program doesn't read, so all of them go in
+// order through a channel to a writer thread.
+use crate::logging;
+use crate::metrics::{SessionMetrics, WriteTarget, METRICS};
+use crate::persist::{Launch, Scrollback};
+use crate::pty_session::{PtySession, PtyWriter, TeardownPolicy};
+use crate::server::WsSender;
+use crate::transcode::encode_input;
+use crate::utf8::OutputFormat;
+use encoding_rs::Encoding;
+use futures_util::SinkExt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::sync::Mutex as TokioMutex;
+use tokio::task::AbortHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Who may write to a session with several clients
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sharing {
+    /// Only the controlling writer, control moves with `take_control`
+    #[default]
+    Exclusive,
+    /// Every writer
+    Collaborative,
+}
+
+/// Which client sizes the PTY follows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SizePolicy {
+    /// Fits every client
+    #[default]
+    Smallest,
+    /// Fills the biggest client, smaller ones cut off
+    Largest,
+    /// Follows the client that last sent input
+    LastWriter,
+}
+
+/// How a client is attached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    Writer,
+    /// Receives output but can't write or take control
+    Observer,
+}
+
+struct Client {
+    sender: WsSender,
+    /// Frames for the client's send task, `None` once it fell behind
+    queue: Option<mpsc::Sender<Message>>,
+    role: Role,
+    format: OutputFormat,
+    /// Last size sent with `resize`
+    size: Option<(u16, u16)>,
+}
+
+struct ClientState {
+    clients: BTreeMap<u64, Client>,
+    next_id: u64,
+    /// Writer whose input is accepted in exclusive sharing
+    controller: Option<u64>,
+    /// Client that last sent accepted input
+    last_writer: Option<u64>,
+    /// Current PTY size
+    size: (u16, u16),
+    /// Set when the last client detached, the session is ending
+    closed: bool,
+}
+
+/// Settings fixed when a session is started
+#[derive(Debug, Clone, Default)]
+pub struct SessionSettings {
+    pub sharing: Sharing,
+    pub size_policy: SizePolicy,
+    /// How processes are terminated when the last client leaves
+    pub teardown: TeardownPolicy,
+    /// Profile lifetime limit
+    pub lifetime: Option<Duration>,
+    /// What the session was started with, saved to restore it
+    pub launch: Launch,
+    /// Output kept for restoring, 0 without persistence
+    pub scrollback_bytes: usize,
+    /// Encoding the PTY starts with, `None` for UTF-8
+    pub encoding: Option<&'static Encoding>,
+    /// Frames queued for a client before it is disconnected as too slow
+    pub client_queue: usize,
+    /// Input writes queued for the PTY before writers have to wait
+    pub input_queue: usize,
+}
+
+/// Result of attaching a client
+pub struct Attached {
+    pub client: u64,
+    /// Writer that lost control to the new client
+    pub displaced: Option<WsSender>,
+}
+
+/// Result of `take_control`
+pub enum TakeControl {
+    /// Observers can't take control
+    Denied,
+    /// The client controls input, `displaced` lost control to it
+    Taken { displaced: Option<WsSender> },
+}
+
+/// Result of `write_input`
+pub enum Input {
+    /// Written to the PTY, with the new PTY size if it changes
+    Written { resize: Option<(u16, u16)> },
+    /// The client doesn't control input
+    Dropped,
+}
+
+/// Result of detaching a client
+pub struct Detached {
+    pub remaining: usize,
+    /// Writer that got control from the leaving client
+    pub new_controller: Option<WsSender>,
+    pub resize: Option<(u16, u16)>,
+}
+
+/// Session with its PTY and attached clients
+pub struct SharedSession {
+    pub id: u64,
+    pub pty: Arc<TokioMutex<PtySession>>,
+    /// Input for the PTY writer thread
+    writes: mpsc::Sender<PtyWrite>,
+    pub metrics: Arc<SessionMetrics>,
+    pub log_ctx: logging::Context,
+    pub settings: SessionSettings,
+    /// End of the profile's lifetime limit
+    pub deadline: Option<tokio::time::Instant>,
+    state: Mutex<ClientState>,
+    /// Session tasks stopped on teardown
+    tasks: Mutex<Vec<AbortHandle>>,
+    scrollback: Mutex<Scrollback>,
+    /// Encoding of PTY output and input, `None` for UTF-8
+    encoding: Mutex<Option<&'static Encoding>>,
+    /// The program enabled bracketed paste (mode 2004)
+    bracketed_paste: AtomicBool,
+}
+
+impl SharedSession {
+    pub fn new(
+        id: u64,
+        pty: PtySession,
+        writer: PtyWriter,
+        size: (u16, u16),
+        settings: SessionSettings,
+        metrics: Arc<SessionMetrics>,
+        log_ctx: logging::Context,
+    ) -> Self {
+        Self {
+            id,
+            pty: Arc::new(TokioMutex::new(pty)),
+            writes: spawn_writer(writer, settings.input_queue, log_ctx),
+            metrics,
+            log_ctx,
+            deadline: settings.lifetime.map(|lifetime| tokio::time::Instant::now() + lifetime),
+            scrollback: Mutex::new(Scrollback::new(settings.scrollback_bytes)),
+            encoding: Mutex::new(settings.encoding),
+            bracketed_paste: AtomicBool::new(false),
+            settings,
+            state: Mutex::new(ClientState {
+                clients: BTreeMap::new(),
+                next_id: 0,
+                controller: None,
+                last_writer: None,
+                size,
+                closed: false,
+            }),
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Stop a task when the session is torn down
+    pub fn add_task(&self, task: AbortHandle) {
+        self.tasks.lock().unwrap().push(task);
+    }
+
+    /// Attach a client, a writer takes control in exclusive sharing.
+    /// `None` if the session is ending.
+    pub fn attach(&self, sender: WsSender, role: Role, format: OutputFormat) -> Option<Attached> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return None;
+        }
+        state.next_id += 1;
+        let client = state.next_id;
+        let (queue, frames) = mpsc::channel(self.settings.client_queue);
+        tokio::spawn(forward(frames, Arc::clone(&sender)));
+        state.clients.insert(client, Client { sender, queue: Some(queue), role, format, size: None });
+
+        let mut displaced = None;
+        if role == Role::Writer && self.settings.sharing == Sharing::Exclusive {
+            if let Some(previous) = state.controller.replace(client) {
+                displaced = state.clients.get(&previous).map(|c| Arc::clone(&c.sender));
+            }
+        }
+        Some(Attached { client, displaced })
+    }
+
+    /// Give control to a writer in exclusive sharing
+    pub fn take_control(&self, client: u64) -> TakeControl {
+        let mut state = self.state.lock().unwrap();
+        if state.clients.get(&client).map(|c| c.role) != Some(Role::Writer) {
+            return TakeControl::Denied;
+        }
+        if self.settings.sharing == Sharing::Collaborative || state.controller == Some(client) {
+            return TakeControl::Taken { displaced: None };
+        }
+        let previous = state.controller.replace(client);
+        let displaced = previous.and_then(|id| state.clients.get(&id)).map(|c| Arc::clone(&c.sender));
+        TakeControl::Taken { displaced }
+    }
+
+    /// Detach a client, control passes to the longest attached writer.
+    /// The session is closed to new clients once none are left.
+    pub fn detach(&self, client: u64) -> Detached {
+        let mut state = self.state.lock().unwrap();
+        state.clients.remove(&client);
+        state.closed = state.clients.is_empty();
+        if state.last_writer == Some(client) {
+            state.last_writer = None;
+        }
+
+        let mut new_controller = None;
+        if state.controller == Some(client) {
+            state.controller = state.clients.iter().find(|(_, c)| c.role == Role::Writer).map(|(&id, _)| id);
+            new_controller = state.controller.and_then(|id| state.clients.get(&id)).map(|c| Arc::clone(&c.sender));
+        }
+        let resize = self.apply_size_policy(&mut state);
+        Detached { remaining: state.clients.len(), new_controller, resize }
+    }
+
+    /// Whether the client controls input (always true for writers in collaborative sharing)
+    pub fn is_controlling(&self, client: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        Self::may_write(&state, self.settings.sharing, client)
+    }
+
+    fn may_write(state: &ClientState, sharing: Sharing, client: u64) -> bool {
+        match state.clients.get(&client).map(|c| c.role) {
+            Some(Role::Writer) => sharing == Sharing::Collaborative || state.controller == Some(client),
+            _ => false,
+        }
+    }
+
+    /// Record a client's size, returns the new PTY size if it changes
+    pub fn set_client_size(&self, client: u64, cols: u16, rows: u16) -> Option<(u16, u16)> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(c) = state.clients.get_mut(&client) {
+            c.size = Some((cols, rows));
+        }
+        self.apply_size_policy(&mut state)
+    }
+
+    /// Write client input to the PTY if the client may write. Waits while
+    /// the input queue is full.
+    pub async fn write_input(&self, client: u64, data: &[u8]) -> Result<Input, String> {
+        let Some(resize) = self.accept_input(client) else {
+            return Ok(Input::Dropped);
+        };
+
+        let encoded = match self.encoding() {
+            Some(encoding) => encode_input(encoding, data),
+            None => data.to_vec(),
+        };
+        self.queue_write(PtyWrite { chunks: vec![encoded], delay: Duration::ZERO, written: None }).await?;
+        self.metrics.record_in(data.len());
+        Ok(Input::Written { resize })
+    }
+
+    /// Write a paste in chunks of `chunk_bytes` with `delay` between them.
+    /// The paste is queued as one write, so other input can't land inside it.
+    pub async fn write_paste(&self, client: u64, data: &[u8], chunk_bytes: usize, delay: Duration) -> Result<Input, String> {
+        let Some(resize) = self.accept_input(client) else {
+            return Ok(Input::Dropped);
+        };
+
+        let data = match self.encoding() {
+            Some(encoding) => encode_input(encoding, data),
+            None => data.to_vec(),
+        };
+        let chunks = data.chunks(chunk_bytes).map(<[u8]>::to_vec).collect();
+        let (written, done) = oneshot::channel();
+        self.queue_write(PtyWrite { chunks, delay, written: Some(written) }).await?;
+        done.await.map_err(|_| "PTY writer stopped".to_string())??;
+        self.metrics.record_in(data.len());
+        Ok(Input::Written { resize })
+    }
+
+    /// Check that the client may write and apply the size policy, `None` if it may not
+    fn accept_input(&self, client: u64) -> Option<Option<(u16, u16)>> {
+        let mut state = self.state.lock().unwrap();
+        if !Self::may_write(&state, self.settings.sharing, client) {
+            return None;
+        }
+        state.last_writer = Some(client);
+        Some(self.apply_size_policy(&mut state))
+    }
+
+    pub fn bracketed_paste(&self) -> bool {
+        self.bracketed_paste.load(Ordering::Relaxed)
+    }
+
+    pub fn set_bracketed_paste(&self, enabled: bool) {
+        self.bracketed_paste.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn encoding(&self) -> Option<&'static Encoding> {
+        *self.encoding.lock().unwrap()
+    }
+
+    /// Switch the encoding of output and input
+    pub fn set_encoding(&self, encoding: Option<&'static Encoding>) {
+        *self.encoding.lock().unwrap() = encoding;
+    }
+
+    /// Hand a write to the writer thread, it is written after everything queued before
+    async fn queue_write(&self, write: PtyWrite) -> Result<(), String> {
+        self.writes.send(write).await.map_err(|_| "PTY writer stopped".to_string())
+    }
+
+    /// Queue a frame for every client
+    pub fn broadcast(&self, message: Message) {
+        self.broadcast_output(|_| message.clone());
+    }
+
+    /// Queue output for every client, `frame` builds it once per output format
+    pub fn broadcast_output(&self, frame: impl Fn(OutputFormat) -> Message) {
+        let mut state = self.state.lock().unwrap();
+        let mut frames: Vec<(OutputFormat, Message)> = Vec::new();
+        for (&id, client) in &mut state.clients {
+            let Some(queue) = &client.queue else {
+                continue;
+            };
+            let message = match frames.iter().find(|(f, _)| *f == client.format) {
+                Some((_, message)) => message.clone(),
+                None => {
+                    let message = frame(client.format);
+                    frames.push((client.format, message.clone()));
+                    message
+                }
+            };
+            match queue.try_send(message) {
+                Ok(()) => {}
+                // The connection task detaches the client once it is gone
+                Err(mpsc::error::TrySendError::Closed(_)) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    log_warn!(ctx: self.log_ctx, "Client {} fell {} frames behind, disconnecting it", id, queue.max_capacity());
+                    METRICS.write_error(WriteTarget::Client);
+                    // Its send task ends with what it has, closing the connection ends its connection task
+                    client.queue = None;
+                    let sender = Arc::clone(&client.sender);
+                    tokio::spawn(async move {
+                        let _ = sender.lock().await.close().await;
+                    });
+                }
+            }
+        }
+    }
+
+    /// Keep output sent to clients for restoring the session
+    pub fn record_output(&self, data: &[u8]) {
+        self.scrollback.lock().unwrap().push(data);
+    }
+
+    /// Kept output and the Unix time of the last output
+    pub fn scrollback(&self) -> (Vec<u8>, u64) {
+        let scrollback = self.scrollback.lock().unwrap();
+        (scrollback.tail().to_vec(), scrollback.updated_at())
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.state.lock().unwrap().clients.len()
+    }
+
+    /// Stop session tasks, once the last client has left
+    pub fn stop_tasks(&self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+
+    /// Compute the PTY size from client sizes, returns it if it changed
+    fn apply_size_policy(&self, state: &mut ClientState) -> Option<(u16, u16)> {
+        let sizes: Vec<(u16, u16)> = state.clients.values().filter_map(|c| c.size).collect();
+        let last_writer = state
+            .last_writer
+            .or(state.controller)
+            .and_then(|id| state.clients.get(&id))
+            .and_then(|c| c.size);
+        let size = target_size(self.settings.size_policy, &sizes, last_writer)?;
+        if size == state.size {
+            return None;
+        }
+        state.size = size;
+        Some(size)
+    }
+}
+
+/// Input for the PTY writer thread, chunks are written back to back with
+/// `delay` between them and `written` is told the result
+struct PtyWrite {
+    chunks: Vec<Vec<u8>>,
+    delay: Duration,
+    written: Option<oneshot::Sender<Result<(), String>>>,
+}
+
+/// Start the thread that owns the PTY writer, it ends with the session
+fn spawn_writer(mut writer: PtyWriter, queue_size: usize, log_ctx: logging::Context) -> mpsc::Sender<PtyWrite> {
+    let (writes, mut queue) = mpsc::channel::<PtyWrite>(queue_size);
+    std::thread::spawn(move || {
+        while let Some(write) = queue.blocking_recv() {
+            let mut result = Ok(());
+            for (i, chunk) in write.chunks.iter().enumerate() {
+                if i > 0 {
+                    std::thread::sleep(write.delay);
+                }
+                result = writer.write(chunk).map_err(|e| e.to_string());
+                if result.is_err() {
+                    break;
+                }
+            }
+            if let Err(e) = &result {
+                METRICS.write_error(WriteTarget::Pty);
+                log_error!(ctx: log_ctx, "Failed to write to PTY: {}", e);
+            }
+            if let Some(written) = write.written {
+                let _ = written.send(result);
+            }
+        }
+    });
+    writes
+}
+
+/// Send a client's queued frames until it is detached or dropped
+async fn forward(mut frames: mpsc::Receiver<Message>, sender: WsSender) {
+    while let Some(message) = frames.recv().await {
+        // A failing client is detached by its own connection task
+        if sender.lock().await.send(message).await.is_err() {
+            METRICS.write_error(WriteTarget::Client);
+            return;
+        }
+        METRICS.frame_sent();
+    }
+}
+
+/// PTY size for client sizes in attach order, `None` while no client reported one
+fn target_size(policy: SizePolicy, sizes: &[(u16, u16)], last_writer: Option<(u16, u16)>) -> Option<(u16, u16)> {
+    let sizes = sizes.iter().copied();
+    match policy {
+        SizePolicy::Smallest => sizes.reduce(|a, b| (a.0.min(b.0), a.1.min(b.1))),
+        SizePolicy::Largest => sizes.reduce(|a, b| (a.0.max(b.0), a.1.max(b.1))),
+        SizePolicy::LastWriter => last_writer.or_else(|| sizes.last()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_size() {
+        let sizes = [(120, 40), (80, 50)];
+        assert_eq!(target_size(SizePolicy::Smallest, &sizes, None), Some((80, 40)));
+        assert_eq!(target_size(SizePolicy::Largest, &sizes, None), Some((120, 50)));
+        assert_eq!(target_size(SizePolicy::LastWriter, &sizes, Some((120, 40))), Some((120, 40)));
+        assert_eq!(target_size(SizePolicy::LastWriter, &sizes, None), Some((80, 50)));
+        assert_eq!(target_size(SizePolicy::Smallest, &[], None), None);
+    }
+}
@@ -1,48 +1,128 @@
 // Shell Detection and Configuration
 use portable_pty::CommandBuilder;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-/// Shell Integration scripts (injected via PTY)
-/// Use space prefix to prevent command from entering history, use redirect to hide output
-/// Note: bash/zsh default config doesn't record commands starting with space
-/// Only used on Unix platforms, Windows relies on frontend prompt parsing
+/// Time allowed for `<shell> --version` during discovery
+const VERSION_TIMEOUT: Duration = Duration::from_secs(2);
 
-// Bash: Define function and set PROMPT_COMMAND, execute silently
+/// Shells looked up in PATH during discovery: (shell_type, program, version flag)
 #[cfg(not(windows))]
-const SHELL_INTEGRATION_BASH: &str = " eval '__sw_cwd(){ printf \"\\e]7;file://%s%s\\e\\\\\" \"${HOSTNAME:-localhost}\" \"$PWD\";};PROMPT_COMMAND=\"__sw_cwd${PROMPT_COMMAND:+;$PROMPT_COMMAND}\"' 2>/dev/null;__sw_cwd;printf '\\ec'\n";
+const KNOWN_SHELLS: &[(&str, &str, &str)] = &[
+    ("bash", "bash", "--version"),
+    ("zsh", "zsh", "--version"),
+    ("fish", "fish", "--version"),
+    ("nu", "nu", "--version"),
+    ("pwsh", "pwsh", "--version"),
+    ("xonsh", "xonsh", "--version"),
+    ("elvish", "elvish", "-version"),
+];
 
-// Zsh: Use precmd hook, execute silently
-#[cfg(not(windows))]
-const SHELL_INTEGRATION_ZSH: &str = " eval '__sw_cwd(){ printf \"\\e]7;file://%s%s\\e\\\\\" \"${HOST:-localhost}\" \"$PWD\";};autoload -Uz add-zsh-hook;add-zsh-hook precmd __sw_cwd;add-zsh-hook chpwd __sw_cwd' 2>/dev/null;__sw_cwd;printf '\\ec'\n";
+#[cfg(windows)]
+const KNOWN_SHELLS: &[(&str, &str, &str)] = &[
+    ("cmd", "cmd.exe", ""),
+    ("powershell", "pwsh.exe", "--version"),
+    ("powershell", "powershell.exe", ""),
+    ("wsl", "wsl.exe", ""),
+    ("nu", "nu.exe", "--version"),
+];
 
-// Fish: Use event listener
-#[cfg(not(windows))]
-const SHELL_INTEGRATION_FISH: &str = " eval 'function __sw_cwd --on-variable PWD; printf \"\\e]7;file://%s%s\\e\\\\\" (hostname) $PWD; end' 2>/dev/null;__sw_cwd;printf '\\ec'\n";
+/// Installed shell found by discovery
+#[derive(Debug, Clone, Serialize)]
+pub struct ShellEntry {
+    /// Value to pass as `shell_type` in init
+    pub shell_type: String,
+    /// Resolved executable path
+    pub path: String,
+    pub version: Option<String>,
+    /// Whether cwd tracking via shell integration is available
+    pub integration: bool,
+}
+
+/// Invalid `custom:` shell specification
+#[derive(Debug)]
+pub enum ShellSpecError {
+    /// Nothing after the `custom:` prefix
+    Empty,
+    /// Unbalanced quotes
+    Parse(String),
+    /// Program not found as a path or in PATH
+    NotFound(String),
+    /// Program exists but is not an executable file
+    NotExecutable(String),
+}
+
+impl ShellSpecError {
+    /// Stable error code reported to clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            ShellSpecError::Empty | ShellSpecError::Parse(_) => "invalid_shell_spec",
+            ShellSpecError::NotFound(_) => "shell_not_found",
+            ShellSpecError::NotExecutable(_) => "shell_not_executable",
+        }
+    }
+}
+
+impl std::fmt::Display for ShellSpecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShellSpecError::Empty => write!(f, "Custom shell specification is empty"),
+            ShellSpecError::Parse(e) => write!(f, "Cannot parse custom shell specification: {}", e),
+            ShellSpecError::NotFound(program) => write!(f, "Shell not found: {}", program),
+            ShellSpecError::NotExecutable(program) => write!(f, "Shell is not executable: {}", program),
+        }
+    }
+}
+
+impl std::error::Error for ShellSpecError {}
+
+/// Parse `custom:` shell specification into a command, e.g. `/usr/bin/env -S zsh -l`.
+/// A spec naming an existing file is taken as-is, so unquoted paths with spaces keep working.
+pub fn parse_custom_shell(spec: &str) -> Result<CommandBuilder, ShellSpecError> {
+    let spec = spec.trim();
+    if spec.is_empty() {
+        return Err(ShellSpecError::Empty);
+    }
+    if Path::new(spec).is_file() {
+        return resolve_program(spec).map(CommandBuilder::new);
+    }
 
-/// Get shell integration script
-/// Note: Windows platform shells don't use Shell Integration, rely on frontend prompt parsing
-pub fn get_shell_integration_script(shell_type: &str) -> Option<&'static str> {
-    // Windows platform doesn't inject scripts
+    // Backslashes are path separators on Windows, not escapes
     #[cfg(windows)]
-    {
-        let _ = shell_type; // Avoid unused warning
-        None
+    let spec = &spec.replace('\\', "\\\\");
+
+    let words = shell_words::split(spec).map_err(|e| ShellSpecError::Parse(e.to_string()))?;
+    let (program, args) = words.split_first().ok_or(ShellSpecError::Empty)?;
+
+    let mut cmd = CommandBuilder::new(resolve_program(program)?);
+    cmd.args(args);
+    Ok(cmd)
+}
+
+/// Resolve a program against PATH and check it can be executed
+fn resolve_program(program: &str) -> Result<PathBuf, ShellSpecError> {
+    if let Some(path) = which(program) {
+        return Ok(path);
     }
-    
-    // Unix platform uses Shell Integration
-    #[cfg(not(windows))]
-    {
-        match shell_type {
-            "bash" => Some(SHELL_INTEGRATION_BASH),
-            "zsh" => Some(SHELL_INTEGRATION_ZSH),
-            "fish" => Some(SHELL_INTEGRATION_FISH),
-            _ => None,
+
+    #[cfg(windows)]
+    if !program.contains(['/', '\\']) {
+        if let Some(path) = which(&format!("{}.exe", program)) {
+            return Ok(path);
         }
     }
+
+    if Path::new(program).exists() {
+        Err(ShellSpecError::NotExecutable(program.to_string()))
+    } else {
+        Err(ShellSpecError::NotFound(program.to_string()))
+    }
 }
 
 /// Get Shell command based on shell type
-pub fn get_shell_by_type(shell_type: Option<&str>) -> CommandBuilder {
-    match shell_type {
+pub fn get_shell_by_type(shell_type: Option<&str>) -> Result<CommandBuilder, ShellSpecError> {
+    let cmd = match shell_type {
         Some("cmd") => CommandBuilder::new("cmd.exe"),
         Some("powershell") => {
             #[cfg(windows)]
@@ -56,10 +136,15 @@ pub fn get_shell_by_type(shell_type: Option<&str>) -> CommandBuilder {
             }
             #[cfg(not(windows))]
             {
-                // Non-Windows platform, use default shell
-                get_default_shell()
+                // Non-Windows platform, use PowerShell Core if installed
+                if which("pwsh").is_some() {
+                    CommandBuilder::new("pwsh")
+                } else {
+                    get_default_shell()
+                }
             }
         }
+        Some("pwsh") => CommandBuilder::new("pwsh"),
         Some("wsl") => CommandBuilder::new("wsl.exe"),
         Some("gitbash") => {
             #[cfg(windows)]
@@ -83,13 +168,199 @@ pub fn get_shell_by_type(shell_type: Option<&str>) -> CommandBuilder {
         }
         Some("bash") => CommandBuilder::new("bash"),
         Some("zsh") => CommandBuilder::new("zsh"),
+        Some("fish") => CommandBuilder::new("fish"),
+        Some("nu") => CommandBuilder::new("nu"),
+        Some("xonsh") => CommandBuilder::new("xonsh"),
+        Some("elvish") => CommandBuilder::new("elvish"),
         Some(custom) if custom.starts_with("custom:") => {
-            // Custom shell path, format: "custom:/path/to/shell"
-            let path = &custom[7..]; // Remove "custom:" prefix
-            CommandBuilder::new(path)
+            // Custom shell, format: "custom:/path/to/shell --arg 'quoted arg'"
+            parse_custom_shell(&custom[7..])? // Remove "custom:" prefix
         }
         _ => get_default_shell(), // None or unknown type, use default
+    };
+    Ok(cmd)
+}
+
+/// Login and interactive startup requested for a session
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LaunchMode {
+    /// Start as a login shell, so profile files are sourced
+    pub login: bool,
+    /// Force interactive mode
+    pub interactive: bool,
+}
+
+/// Login and interactive flags per shell: (program name, login flag, interactive flag)
+const LAUNCH_FLAGS: &[(&str, Option<&str>, Option<&str>)] = &[
+    ("bash", Some("-l"), Some("-i")),
+    ("zsh", Some("-l"), Some("-i")),
+    ("sh", Some("-l"), Some("-i")),
+    ("dash", Some("-l"), Some("-i")),
+    ("ksh", Some("-l"), Some("-i")),
+    ("mksh", Some("-l"), Some("-i")),
+    ("csh", Some("-l"), Some("-i")),
+    ("tcsh", Some("-l"), Some("-i")),
+    ("fish", Some("--login"), Some("--interactive")),
+    ("nu", Some("--login"), Some("--interactive")),
+    ("xonsh", Some("--login"), Some("--interactive")),
+    ("pwsh", Some("-Login"), Some("-Interactive")),
+];
+
+/// Shells started as login shells through a dash-prefixed argv[0] (`-zsh`)
+#[cfg(not(windows))]
+const DASH_LOGIN_SHELLS: &[&str] = &["zsh", "sh", "dash", "ksh", "mksh", "csh", "tcsh"];
+
+/// Executable name of a command without directory and `.exe`.
+/// Commands started through a dash-prefixed argv[0] are named after `SHELL`.
+pub fn program_name(cmd: &CommandBuilder) -> Option<&str> {
+    let program = match cmd.get_argv().first() {
+        Some(program) => program.to_str()?,
+        None => cmd.get_env("SHELL")?.to_str()?,
+    };
+    let name = program.rsplit(['/', '\\']).next().unwrap_or(program);
+    Some(name.strip_suffix(".exe").unwrap_or(name))
+}
+
+/// Translate login/interactive mode into the shell's own startup convention.
+/// Call before user arguments are added, `has_user_args` tells whether any follow.
+pub fn apply_launch_mode(mut cmd: CommandBuilder, mode: LaunchMode, has_user_args: bool) -> CommandBuilder {
+    let Some(name) = program_name(&cmd) else {
+        return cmd;
+    };
+    let Some(&(_, login_flag, interactive_flag)) = LAUNCH_FLAGS.iter().find(|(shell, _, _)| *shell == name) else {
+        return cmd;
+    };
+
+    // argv[0] can only be prefixed for a bare shell, a PTY makes it interactive anyway
+    #[cfg(not(windows))]
+    if mode.login && DASH_LOGIN_SHELLS.contains(&name) && cmd.get_argv().len() == 1 && !has_user_args {
+        let program = cmd.get_argv()[0].to_string_lossy().into_owned();
+        if let Some(path) = which(&program) {
+            let mut login = CommandBuilder::new_default_prog();
+            login.env("SHELL", path);
+            return login;
+        }
+    }
+    #[cfg(windows)]
+    let _ = has_user_args;
+
+    let flags = [(mode.login, login_flag), (mode.interactive, interactive_flag)];
+    let argv = cmd.get_argv_mut();
+    let mut position = 1;
+    for flag in flags.into_iter().filter_map(|(enabled, flag)| flag.filter(|_| enabled)) {
+        // Git Bash is already started with --login, custom specs may carry flags too
+        if !argv.iter().skip(1).any(|arg| arg == flag) {
+            argv.insert(position, flag.into());
+            position += 1;
+        }
+    }
+    cmd
+}
+
+/// List installed shells from PATH and /etc/shells, with resolved paths and versions.
+/// Spawns each shell once to query its version, call from a blocking context.
+pub fn discover_shells() -> Vec<ShellEntry> {
+    let mut entries: Vec<ShellEntry> = Vec::new();
+    let mut seen: Vec<PathBuf> = Vec::new();
+
+    let mut add = |shell_type: String, path: PathBuf, version_flag: &str| {
+        let canonical = std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if seen.contains(&canonical) {
+            return;
+        }
+        seen.push(canonical);
+
+        let version = if version_flag.is_empty() { None } else { shell_version(&path, version_flag) };
+        let integration = crate::integration::is_supported(&shell_type);
+        entries.push(ShellEntry {
+            shell_type,
+            path: path.to_string_lossy().into_owned(),
+            version,
+            integration,
+        });
+    };
+
+    for &(shell_type, program, version_flag) in KNOWN_SHELLS {
+        if let Some(path) = which(program) {
+            add(shell_type.to_string(), path, version_flag);
+        }
+    }
+
+    #[cfg(not(windows))]
+    for line in std::fs::read_to_string("/etc/shells").unwrap_or_default().lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || !is_executable(Path::new(line)) {
+            continue;
+        }
+        let name = line.rsplit('/').next().unwrap_or(line);
+        match KNOWN_SHELLS.iter().find(|(_, program, _)| *program == name) {
+            Some(&(shell_type, _, version_flag)) => add(shell_type.to_string(), PathBuf::from(line), version_flag),
+            None => add(format!("custom:{}", line), PathBuf::from(line), ""),
+        }
+    }
+
+    entries
+}
+
+/// Resolve a program name against PATH
+pub fn which(program: &str) -> Option<PathBuf> {
+    let candidate = Path::new(program);
+    if candidate.components().count() > 1 {
+        return is_executable(candidate).then(|| candidate.to_path_buf());
     }
+
+    let path_var = std::env::var_os("PATH")?;
+    std::env::split_paths(&path_var)
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
+}
+
+/// Check that a path is an executable file
+pub fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path)
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    }
+
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+/// Run `<shell> <flag>` and return the first non-empty output line
+fn shell_version(path: &Path, flag: &str) -> Option<String> {
+    let mut child = std::process::Command::new(path)
+        .arg(flag)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .ok()?;
+
+    // Don't let a misbehaving shell hang discovery
+    let deadline = Instant::now() + VERSION_TIMEOUT;
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(20)),
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+    }
+
+    let output = child.wait_with_output().ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
 }
 
 /// Get default Shell command
@@ -180,4 +451,46 @@ mod tests {
         let _shell = get_default_shell();
         // If we reach here, function works correctly
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_custom_shell() {
+        let cmd = parse_custom_shell("env -S 'zsh -l'").unwrap();
+        let argv: Vec<_> = cmd.get_argv().iter().map(|a| a.to_string_lossy().into_owned()).collect();
+        assert!(argv[0].ends_with("/env"));
+        assert_eq!(&argv[1..], ["-S", "zsh -l"]);
+
+        assert_eq!(parse_custom_shell("/no/such/shell -l").unwrap_err().code(), "shell_not_found");
+        assert_eq!(parse_custom_shell("/etc/passwd").unwrap_err().code(), "shell_not_executable");
+        assert_eq!(parse_custom_shell("zsh 'unterminated").unwrap_err().code(), "invalid_shell_spec");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_launch_mode() {
+        let argv = |cmd: &CommandBuilder| -> Vec<String> {
+            cmd.get_argv().iter().map(|a| a.to_string_lossy().into_owned()).collect()
+        };
+        let both = LaunchMode { login: true, interactive: true };
+
+        let cmd = apply_launch_mode(CommandBuilder::new("fish"), both, true);
+        assert_eq!(argv(&cmd), ["fish", "--login", "--interactive"]);
+
+        let mut gitbash = CommandBuilder::new("bash");
+        gitbash.arg("-l");
+        assert_eq!(argv(&apply_launch_mode(gitbash, both, false)), ["bash", "-i", "-l"]);
+
+        let cmd = apply_launch_mode(CommandBuilder::new("elvish"), both, false);
+        assert_eq!(argv(&cmd), ["elvish"]);
+
+        if let Some(path) = which("sh") {
+            let cmd = apply_launch_mode(CommandBuilder::new("sh"), both, false);
+            assert!(cmd.is_default_prog());
+            assert_eq!(cmd.get_env("SHELL"), Some(path.as_os_str()));
+            assert_eq!(program_name(&cmd), Some("sh"));
+
+            let cmd = apply_launch_mode(CommandBuilder::new("sh"), both, true);
+            assert_eq!(argv(&cmd), ["sh", "-l", "-i"]);
+        }
+    }
 }
@@ -0,0 +1,32 @@
+// Temporary directories for tests
+//
+// A directory under the system temp dir named after the test and the process,
+// removed with everything in it when the guard is dropped, also when the test
+// fails.
+use std::path::{Path, PathBuf};
+
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create an empty directory, `name` keeps tests running in parallel apart
+    pub fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("pty-server-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,109 @@
+// Legacy Encodings
+//
+// Some tools, and remote hosts reached via ssh, still speak GBK, Shift_JIS or
+// Latin-1, which xterm.js shows as mojibake. A session started with
+// `encoding` (or switched with `set_encoding`) has its output decoded to
+// UTF-8 before anything else looks at it, so OSC parsing and hyperlinking
+// work as usual, and client input encoded back. Characters the encoding
+// can't represent are typed as `?`.
+use encoding_rs::{CoderResult, Decoder, Encoding, EncoderResult, UTF_8};
+
+/// Encoding for a label such as `gbk`, `shift_jis` or `latin1`, `None` for UTF-8
+pub fn lookup(label: &str) -> Result<Option<&'static Encoding>, String> {
+    match Encoding::for_label(label.trim().as_bytes()) {
+        Some(encoding) if encoding == UTF_8 => Ok(None),
+        // UTF-16 and the replacement encoding can't encode input
+        Some(encoding) if encoding.output_encoding() == encoding => Ok(Some(encoding)),
+        _ => Err(format!("Unsupported encoding: {}", label)),
+    }
+}
+
+/// Name reported to clients
+pub fn name(encoding: Option<&'static Encoding>) -> &'static str {
+    encoding.unwrap_or(UTF_8).name()
+}
+
+/// Decodes PTY output to UTF-8, following encoding switches
+pub struct OutputDecoder {
+    encoding: Option<&'static Encoding>,
+    decoder: Option<Decoder>,
+}
+
+impl OutputDecoder {
+    pub fn new(encoding: Option<&'static Encoding>) -> Self {
+        Self { encoding, decoder: encoding.map(Encoding::new_decoder_without_bom_handling) }
+    }
+
+    /// Decode with `encoding` from now on, a character cut off by the switch is dropped
+    pub fn follow(&mut self, encoding: Option<&'static Encoding>) {
+        if encoding != self.encoding {
+            *self = Self::new(encoding);
+        }
+    }
+
+    /// Output as UTF-8, a character split across reads is completed by the next one
+    pub fn decode(&mut self, data: Vec<u8>) -> Vec<u8> {
+        let Some(decoder) = self.decoder.as_mut() else {
+            return data;
+        };
+        let mut text = String::new();
+        let mut rest = &data[..];
+        loop {
+            text.reserve(decoder.max_utf8_buffer_length(rest.len()).unwrap_or(rest.len() * 3));
+            let (result, read, _) = decoder.decode_to_string(rest, &mut text, false);
+            rest = &rest[read..];
+            if result == CoderResult::InputEmpty {
+                return text.into_bytes();
+            }
+        }
+    }
+}
+
+/// Client input (UTF-8) in the session's encoding
+pub fn encode_input(encoding: &'static Encoding, data: &[u8]) -> Vec<u8> {
+    let text = String::from_utf8_lossy(data);
+    let mut encoder = encoding.new_encoder();
+    let mut encoded = Vec::with_capacity(text.len());
+    let mut buf = [0u8; 1024];
+    let mut rest = &text[..];
+    loop {
+        let (result, read, written) = encoder.encode_from_utf8_without_replacement(rest, &mut buf, true);
+        encoded.extend_from_slice(&buf[..written]);
+        rest = &rest[read..];
+        match result {
+            EncoderResult::InputEmpty => return encoded,
+            EncoderResult::OutputFull => {}
+            EncoderResult::Unmappable(_) => encoded.push(b'?'),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup("utf-8"), Ok(None));
+        assert_eq!(lookup("GBK").map(name), Ok("GBK"));
+        assert_eq!(lookup("latin1").map(name), Ok("windows-1252"));
+        assert!(lookup("utf-16le").is_err());
+        assert!(lookup("klingon").is_err());
+    }
+
+    #[test]
+    fn test_round_trip_split_output() {
+        let gbk = lookup("gbk").unwrap();
+        let encoded = encode_input(gbk.unwrap(), "终端 ✓".as_bytes());
+        // Check mark isn't in GBK
+        assert_eq!(encoded, b"\xD6\xD5\xB6\xCB ?");
+
+        let mut decoder = OutputDecoder::new(gbk);
+        let mut output = decoder.decode(encoded[..3].to_vec());
+        output.extend(decoder.decode(encoded[3..].to_vec()));
+        assert_eq!(String::from_utf8(output).unwrap(), "终端 ?");
+
+        decoder.follow(None);
+        assert_eq!(decoder.decode(b"\xFFraw".to_vec()), b"\xFFraw");
+    }
+}
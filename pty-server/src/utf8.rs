@@ -0,0 +1,137 @@
+// UTF-8 Output Handling
+//
+// PTY reads end wherever the buffer is full, which may be in the middle of a
+// multi-byte character (CJK text is three bytes per character). The read
+// task holds back such an incomplete sequence until the next read, so no
+// frame ever ends with half a character. Clients that prefer strings over
+// binary frames can ask for text output; bytes that still aren't valid
+// UTF-8 are then replaced, dropped or escaped as the client chooses.
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+/// How a client receives terminal output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputMode {
+    /// Raw bytes in binary frames
+    #[default]
+    Binary,
+    /// `output` events in text frames
+    Text,
+}
+
+/// What text output does with bytes that aren't valid UTF-8
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidUtf8 {
+    /// U+FFFD for each invalid sequence
+    #[default]
+    Replace,
+    Drop,
+    /// `\xNN` for each invalid byte
+    Escape,
+}
+
+/// Output settings of a client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputFormat {
+    pub mode: OutputMode,
+    pub invalid_utf8: InvalidUtf8,
+}
+
+/// Holds back an incomplete UTF-8 sequence at the end of a read until the next one
+#[derive(Debug, Default)]
+pub struct Utf8Boundary {
+    pending: Vec<u8>,
+}
+
+impl Utf8Boundary {
+    /// Output ready to send, without a trailing incomplete character
+    pub fn complete(&mut self, mut data: Vec<u8>) -> Vec<u8> {
+        if !self.pending.is_empty() {
+            data.splice(..0, self.pending.drain(..));
+        }
+        let keep = incomplete_tail(&data);
+        self.pending = data.split_off(data.len() - keep);
+        data
+    }
+
+    /// Bytes still held back, at the end of the output
+    pub fn flush(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Length of a UTF-8 sequence cut off at the end of `data`
+fn incomplete_tail(data: &[u8]) -> usize {
+    // A sequence is at most 4 bytes, so its lead byte is within the last 3
+    for back in 1..=data.len().min(3) {
+        let byte = data[data.len() - back];
+        if byte & 0xC0 == 0x80 {
+            continue;
+        }
+        let len = match byte {
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF4 => 4,
+            _ => return 0,
+        };
+        return if len > back { back } else { 0 };
+    }
+    0
+}
+
+/// Output as a string, handling invalid bytes by `invalid`
+pub fn to_text(data: &[u8], invalid: InvalidUtf8) -> String {
+    if invalid == InvalidUtf8::Replace {
+        return String::from_utf8_lossy(data).into_owned();
+    }
+
+    let mut text = String::with_capacity(data.len());
+    let mut rest = data;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                text.push_str(valid);
+                return text;
+            }
+            Err(e) => {
+                let (valid, invalid_start) = rest.split_at(e.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                let bad = e.error_len().unwrap_or(invalid_start.len());
+                if invalid == InvalidUtf8::Escape {
+                    for byte in &invalid_start[..bad] {
+                        let _ = write!(text, "\\x{:02X}", byte);
+                    }
+                }
+                rest = &invalid_start[bad..];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boundary_holds_back_partial_character() {
+        let text = "终端".as_bytes();
+        let mut boundary = Utf8Boundary::default();
+        assert_eq!(boundary.complete(text[..4].to_vec()), "终".as_bytes());
+        assert_eq!(boundary.complete(text[4..].to_vec()), "端".as_bytes());
+        assert!(boundary.flush().is_empty());
+
+        // Invalid bytes pass, a lone lead byte waits for the next read
+        assert_eq!(boundary.complete(vec![b'a', 0xFF, 0xE7]), vec![b'a', 0xFF]);
+        assert_eq!(boundary.flush(), vec![0xE7]);
+    }
+
+    #[test]
+    fn test_to_text() {
+        let data = b"ok\xFFend\xE7";
+        assert_eq!(to_text(data, InvalidUtf8::Replace), "ok\u{FFFD}end\u{FFFD}");
+        assert_eq!(to_text(data, InvalidUtf8::Drop), "okend");
+        assert_eq!(to_text(data, InvalidUtf8::Escape), "ok\\xFFend\\xE7");
+    }
+}
@@ -0,0 +1,89 @@
+// Browser Terminal
+//
+// With `[web] enabled = true` the listener serves a small terminal page at
+// `/terminal`, so a vault shell can be reached from a browser without
+// Obsidian. The page, its script and the pinned xterm.js files in
+// `web/vendor` are compiled into the binary and served from the same origin,
+// so no third-party host can read the auth token the page uses for its
+// WebSocket. The page itself is only served with that token.
+use crate::config::WebSettings;
+use serde::Serialize;
+
+const TERMINAL_HTML: &str = include_str!("../web/terminal.html");
+const TERMINAL_JS: &str = include_str!("../web/terminal.js");
+const XTERM_JS: &str = include_str!("../web/vendor/xterm.js");
+const XTERM_CSS: &str = include_str!("../web/vendor/xterm.css");
+const ADDON_FIT_JS: &str = include_str!("../web/vendor/addon-fit.js");
+
+/// Shown instead of the page when the token is missing or wrong
+const TOKEN_FORM_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1"><title>Terminal</title></head>
+<body style="font: 15px system-ui, sans-serif; margin: 3em auto; max-width: 24em">
+  <form method="get" action="/terminal">
+    <p><label>Token <input type="password" name="token" autofocus required style="width: 100%"></label></p>
+    <p><button type="submit">Open terminal</button></p>
+  </form>
+</body>
+</html>
+"#;
+
+/// Defaults the page uses when the URL doesn't set them
+#[derive(Debug, Serialize)]
+struct PageDefaults<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shell: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cwd: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vault_root: Option<&'a str>,
+}
+
+/// Terminal page with the configured defaults filled in
+pub fn terminal_page(settings: &WebSettings) -> String {
+    let defaults = PageDefaults {
+        profile: settings.profile.as_deref(),
+        shell: settings.shell.as_deref(),
+        cwd: settings.cwd.as_deref(),
+        vault_root: settings.vault_root.as_deref(),
+    };
+    // Escape `<` so a value can't close the script element
+    let json = serde_json::to_string(&defaults).unwrap_or_else(|_| "{}".to_string()).replace('<', "\\u003c");
+    TERMINAL_HTML.replace("__PTY_DEFAULTS__", &json)
+}
+
+/// Script or stylesheet of the page, with its content type
+pub fn asset(path: &str) -> Option<(&'static str, &'static str)> {
+    let javascript = "text/javascript; charset=utf-8";
+    match path {
+        "/terminal.js" => Some((javascript, TERMINAL_JS)),
+        "/xterm.js" => Some((javascript, XTERM_JS)),
+        "/xterm.css" => Some(("text/css; charset=utf-8", XTERM_CSS)),
+        "/addon-fit.js" => Some((javascript, ADDON_FIT_JS)),
+        _ => None,
+    }
+}
+
+pub fn token_form() -> &'static str {
+    TOKEN_FORM_HTML
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_terminal_page_defaults() {
+        let settings = WebSettings { cwd: Some("/vault</script>".to_string()), ..WebSettings::default() };
+        let page = terminal_page(&settings);
+        assert!(page.contains(r#"window.PTY_DEFAULTS = {"cwd":"/vault\u003c/script>"};"#));
+        assert!(!page.contains("__PTY_DEFAULTS__"));
+        // Everything the page loads is served by the server itself
+        assert!(!page.contains("https://"));
+        for path in ["/terminal.js", "/xterm.js", "/xterm.css", "/addon-fit.js"] {
+            assert!(page.contains(&format!("\"{}\"", &path[1..])) && asset(path).is_some());
+        }
+    }
+}
@@ -0,0 +1,122 @@
+// Browser terminal for pty-server
+//
+// Speaks the same WebSocket protocol as the Obsidian plugin: an init message
+// first, keyboard input as binary frames (so typed text is never mistaken for
+// a JSON command), pastes as `paste` messages, output as binary frames and
+// events as JSON text frames.
+// URL parameters `profile`, `shell` and `cwd` override the server's defaults,
+// `session` (with `role=observer` for read-only) joins a running session.
+(function () {
+  'use strict';
+
+  const params = new URLSearchParams(location.search);
+  const token = params.get('token');
+  const defaults = window.PTY_DEFAULTS || {};
+
+  // Keep the token out of the address bar and history
+  if (token) {
+    params.delete('token');
+    const query = params.toString();
+    history.replaceState(null, '', location.pathname + (query ? '?' + query : ''));
+  }
+
+  const statusEl = document.getElementById('status');
+  function showStatus(message) {
+    statusEl.textContent = message;
+    statusEl.hidden = false;
+  }
+
+  if (typeof Terminal === 'undefined' || typeof FitAddon === 'undefined') {
+    showStatus('Could not load xterm.js');
+    return;
+  }
+
+  const term = new Terminal({
+    cursorBlink: true,
+    fontFamily: 'Menlo, Consolas, "DejaVu Sans Mono", monospace',
+    fontSize: 14,
+    theme: { background: '#1e1e1e' },
+  });
+  const fit = new FitAddon.FitAddon();
+  term.loadAddon(fit);
+  term.open(document.getElementById('terminal'));
+  fit.fit();
+
+  const init = { type: 'init', vault_root: defaults.vault_root || undefined };
+  const profile = params.get('profile') || defaults.profile;
+  const shell = params.get('shell') || defaults.shell;
+  const cwd = params.get('cwd') || defaults.cwd;
+  if (profile) init.profile = profile;
+  if (shell) init.shell_type = shell;
+  if (cwd) init.cwd = cwd;
+
+  const session = params.get('session');
+  const first = session
+    ? { type: 'attach', session: Number(session), role: params.get('role') || undefined }
+    : init;
+
+  const scheme = location.protocol === 'https:' ? 'wss:' : 'ws:';
+  const url = scheme + '//' + location.host + '/' + (token ? '?token=' + encodeURIComponent(token) : '');
+  const ws = new WebSocket(url);
+  ws.binaryType = 'arraybuffer';
+  const encoder = new TextEncoder();
+
+  function sendResize() {
+    if (ws.readyState === WebSocket.OPEN) {
+      ws.send(JSON.stringify({ type: 'resize', cols: term.cols, rows: term.rows }));
+    }
+  }
+
+  ws.onopen = function () {
+    ws.send(JSON.stringify(first));
+    sendResize();
+    term.focus();
+  };
+
+  ws.onmessage = function (event) {
+    if (typeof event.data !== 'string') {
+      term.write(new Uint8Array(event.data));
+      return;
+    }
+    let message;
+    try {
+      message = JSON.parse(event.data);
+    } catch (e) {
+      return;
+    }
+    switch (message.type) {
+      case 'title_changed':
+        document.title = message.title || 'Terminal';
+        break;
+      case 'error':
+        showStatus(message.message);
+        break;
+    }
+  };
+
+  ws.onclose = function () {
+    if (statusEl.hidden) {
+      showStatus('Session closed');
+    }
+  };
+
+  term.onData(function (data) {
+    if (ws.readyState === WebSocket.OPEN) {
+      ws.send(encoder.encode(data));
+    }
+  });
+  // The server brackets, sanitizes and paces pastes, xterm.js doesn't see them
+  document.getElementById('terminal').addEventListener('paste', function (event) {
+    const text = event.clipboardData && event.clipboardData.getData('text/plain');
+    if (!text || ws.readyState !== WebSocket.OPEN) {
+      return;
+    }
+    event.preventDefault();
+    event.stopPropagation();
+    ws.send(JSON.stringify({ type: 'paste', data: text }));
+  }, true);
+  term.onResize(sendResize);
+  window.addEventListener('resize', function () {
+    fit.fit();
+  });
+})();
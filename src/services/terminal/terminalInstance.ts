@@ -12,6 +12,7 @@ import { platform } from 'os';
 import { exec } from 'child_process';
 import { debugLog, debugWarn, errorLog } from '../../utils/logger';
 import { t } from '../../i18n';
+import { NoticeHelper } from '../../ui/noticeHelper';
 
 // electron 是外部模块，使用 require 导入
 // eslint-disable-next-line @typescript-eslint/no-var-requires
@@ -45,6 +46,7 @@ export interface TerminalOptions {
 interface ResizeMessage { type: 'resize'; cols: number; rows: number; }
 interface InitMessage { type: 'init'; shell_type?: string; shell_args?: string[]; cwd?: string; env?: Record<string, string>; }
 type WSInputMessage = string | Uint8Array | ResizeMessage | InitMessage;
+interface ServerEvent { type: string; cwd?: string; in_vault?: boolean; title?: string; body?: string; }
 
 /** 搜索状态变化回调 */
 export type SearchStateCallback = (visible: boolean) => void;
@@ -111,6 +113,17 @@ export class TerminalInstance {
       windowsMode: false, // 禁用 Windows 模式，确保 Ctrl+C 正确发送中断信号
       rightClickSelectsWord: true,
       allowProposedApi: true, // 启用提议的 API,用于搜索高亮装饰
+      // OSC 8 超链接：只打开服务器生成的 obsidian:// 笔记链接，
+      // 程序输出中的其他协议（file://、smb:// 等）一律忽略
+      linkHandler: {
+        activate: (_event, uri) => {
+          if (uri.startsWith('obsidian://')) {
+            shell.openExternal(uri);
+          } else {
+            debugWarn('[Terminal] 忽略非 obsidian:// 超链接:', uri);
+          }
+        }
+      },
     });
 
     this.fitAddon = new FitAddon();
@@ -266,6 +279,8 @@ export class TerminalInstance {
 
         this.ws.onmessage = (event) => {
           if (typeof event.data === 'string') {
+            // 服务器事件（JSON 文本帧）
+            if (this.handleServerEvent(event.data)) return;
             // 尝试提取目录信息（从 OSC 序列）
             this.extractCwdFromOutput(event.data);
             this.xterm.write(event.data);
@@ -1203,6 +1218,34 @@ export class TerminalInstance {
     this.titleChangeCallback?.(title);
   }
 
+  /**
+   * 处理服务器发送的结构化事件
+   * @returns 是否为服务器事件
+   */
+  private handleServerEvent(data: string): boolean {
+    if (!data.startsWith('{"type":')) return false;
+
+    let serverEvent: ServerEvent;
+    try {
+      serverEvent = JSON.parse(data);
+    } catch {
+      return false;
+    }
+
+    switch (serverEvent.type) {
+      case 'cwd_changed':
+        this.currentCwd = serverEvent.cwd ?? this.currentCwd;
+        debugLog('[Terminal CWD] Server reported:', serverEvent.cwd, 'in vault:', serverEvent.in_vault);
+        break;
+      case 'notify':
+        NoticeHelper.info(serverEvent.title ? `${serverEvent.title}: ${serverEvent.body}` : `${serverEvent.body}`);
+        break;
+      default:
+        debugLog('[Terminal] Unhandled server event:', serverEvent.type);
+    }
+    return true;
+  }
+
   /**
    * 从 shell 输出中提取当前工作目录
    * 支持 OSC 序列和 PowerShell/CMD/Git Bash/Bash prompt 格式